@@ -0,0 +1,1083 @@
+// Audio processing unit, ticked at the CPU clock. The frame sequencer is
+// clocked by DIV like on the console. The level changes go through
+// band-limited steps to the host rate, then a high-pass filter like the
+// output capacitor.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+
+use super::blip::Blip;
+
+pub const CPU_FREQ: u32 = 4194304;
+
+const DUTY: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+// Output capacitor of each console, which removes the DC offset of the DACs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterModel {
+    Off,
+    Dmg,
+    Cgb, // smaller, cuts more bass
+}
+
+impl FilterModel {
+    // Fraction of the charge the capacitor keeps each cycle
+    fn charge(self) -> Option<f64> {
+        match self {
+            FilterModel::Off => None,
+            FilterModel::Dmg => Some(0.999958),
+            FilterModel::Cgb => Some(0.998943),
+        }
+    }
+}
+
+impl FromStr for FilterModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(FilterModel::Off),
+            "dmg" => Ok(FilterModel::Dmg),
+            "cgb" => Ok(FilterModel::Cgb),
+            _ => bail!("Unknown audio filter {s}, expected off, dmg or cgb"),
+        }
+    }
+}
+
+struct Length {
+    max: u16,
+    counter: u16,
+    enabled: bool,
+}
+
+impl Length {
+    fn new(max: u16) -> Self {
+        Length { max, counter: 0, enabled: false }
+    }
+
+    fn load(&mut self, val: u16) {
+        self.counter = self.max - val;
+    }
+
+    // When the next frame sequencer step does not clock the length, enabling
+    // it clocks it once more. Returns false when that disables the channel.
+    fn write_enable(&mut self, enabled: bool, trigger: bool, odd_step: bool) -> bool {
+        let extra_clock = odd_step && !self.enabled && enabled && self.counter > 0;
+        self.enabled = enabled;
+        if extra_clock {
+            self.counter -= 1;
+            return self.counter > 0 || trigger;
+        }
+        true
+    }
+
+    fn trigger(&mut self, odd_step: bool) {
+        if self.counter == 0 {
+            self.counter = self.max;
+            if self.enabled && odd_step {
+                self.counter -= 1;
+            }
+        }
+    }
+
+    // Returns true when the channel must be disabled
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+}
+
+#[derive(Default)]
+struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+    running: bool, // stops at volume 0 or 15
+}
+
+impl Envelope {
+    // Writes to a playing channel change the volume ("zombie mode"), the
+    // way most revisions of the console do
+    fn write(&mut self, val: u8, playing: bool) {
+        // The volume is 4 bits, everything wraps around
+        if playing {
+            if self.period == 0 && self.running {
+                self.volume = self.volume.wrapping_add(1);
+            } else if !self.increase {
+                self.volume = self.volume.wrapping_add(2);
+            }
+            if self.increase != (val & 0x08 != 0) {
+                self.volume = 16u8.wrapping_sub(self.volume);
+            }
+            self.volume &= 0xf;
+        }
+        self.initial = val >> 4;
+        self.increase = val & 0x08 != 0;
+        self.period = val & 0x07;
+    }
+
+    // The DAC is off when the register has neither volume nor increase
+    fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+        self.running = true;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 || !self.running {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 0xf {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            } else {
+                self.running = false;
+            }
+        }
+    }
+}
+
+// Frequency sweep of channel 1, computed from a shadow copy of the frequency
+#[derive(Default)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    enabled: bool,
+    shadow: u16,
+    timer: u8,
+    negate_used: bool, // since the last trigger
+}
+
+impl Sweep {
+    // Returns false when the channel must be disabled
+    fn write(&mut self, val: u8) -> bool {
+        self.period = (val >> 4) & 0x7;
+        let negate = val & 0x08 != 0;
+        self.shift = val & 0x7;
+        // Leaving negate mode after it was used disables the channel
+        let keep = negate || !self.negate_used;
+        self.negate = negate;
+        keep
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn next_frequency(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            self.negate_used = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+
+    // Returns false when the overflow check disables the channel
+    fn trigger(&mut self, frequency: u16) -> bool {
+        self.shadow = frequency;
+        self.negate_used = false;
+        self.reload_timer();
+        self.enabled = self.period != 0 || self.shift != 0;
+        self.shift == 0 || self.next_frequency() <= 2047
+    }
+
+    // On frame sequencer steps 2 and 6, returns the new frequency or None
+    // when the channel must be disabled
+    fn clock(&mut self, frequency: u16) -> Option<u16> {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer > 0 {
+            return Some(frequency);
+        }
+        self.reload_timer();
+        if !self.enabled || self.period == 0 {
+            return Some(frequency);
+        }
+        let new = self.next_frequency();
+        if new > 2047 {
+            return None;
+        }
+        if self.shift == 0 {
+            return Some(frequency);
+        }
+        self.shadow = new;
+        // Checked again with the new frequency, but not applied
+        if self.next_frequency() > 2047 {
+            return None;
+        }
+        Some(new)
+    }
+}
+
+struct Square {
+    enabled: bool,
+    sweep: Option<Sweep>, // channel 1 only
+    duty: u8,
+    step: u8,
+    frequency: u16,
+    timer: u16,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Square {
+    fn new(sweep: bool) -> Self {
+        Square {
+            enabled: false,
+            sweep: sweep.then(Sweep::default),
+            duty: 0,
+            step: 0,
+            frequency: 0,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::default(),
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = (2048 - self.frequency) * 4;
+            self.step = (self.step + 1) & 7;
+        }
+    }
+
+    fn trigger(&mut self, odd_step: bool) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(odd_step);
+        self.envelope.trigger();
+        self.timer = (2048 - self.frequency) * 4;
+        if let Some(sweep) = &mut self.sweep {
+            if !sweep.trigger(self.frequency) {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        if let Some(sweep) = &mut self.sweep {
+            match sweep.clock(self.frequency) {
+                Some(frequency) => self.frequency = frequency,
+                None => self.enabled = false,
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && DUTY[self.duty as usize] >> (7 - self.step) & 1 != 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    // odd_step: the next frame sequencer step does not clock the length
+    fn write(&mut self, reg: u16, val: u8, odd_step: bool) {
+        match reg {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    if !sweep.write(val) {
+                        self.enabled = false;
+                    }
+                }
+            }
+            1 => {
+                self.duty = val >> 6;
+                self.length.load((val & 0x3f) as u16);
+            }
+            2 => {
+                self.envelope.write(val, self.enabled);
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = self.frequency & 0x700 | val as u16,
+            4 => {
+                self.frequency = self.frequency & 0xff | ((val & 0x07) as u16) << 8;
+                let trigger = val & 0x80 != 0;
+                if !self.length.write_enable(val & 0x40 != 0, trigger, odd_step) {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger(odd_step);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Plays the 32 nibbles of the wave RAM, fetching a byte as it reaches it
+struct Wave {
+    cgb: bool,
+    dac: bool,
+    enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: u16,
+    position: u8,
+    sample: u8,
+    length: Length,
+    ram: [u8; 16],
+    fetched: bool, // wave RAM was read on the last cycle
+}
+
+impl Wave {
+    fn new(cgb: bool) -> Self {
+        Wave {
+            cgb,
+            dac: false,
+            enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            length: Length::new(256),
+            ram: [0; 16],
+            fetched: false,
+        }
+    }
+
+    fn tick(&mut self) {
+        self.fetched = false;
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = (2048 - self.frequency) * 2;
+            self.position = (self.position + 1) & 31;
+            let byte = self.ram[self.position as usize / 2];
+            self.sample = if self.position & 1 == 0 { byte >> 4 } else { byte & 0xf };
+            self.fetched = true;
+        }
+    }
+
+    // The sample buffer is kept, the first nibble played is the second one
+    fn trigger(&mut self, odd_step: bool) {
+        // Retriggering the DMG as it fetches a byte corrupts the first bytes
+        // of wave RAM with the ones around the fetched byte
+        if !self.cgb && self.enabled && self.timer <= 2 {
+            let next = ((self.position as usize + 1) & 31) / 2;
+            if next < 4 {
+                self.ram[0] = self.ram[next];
+            } else {
+                let block = next & !3;
+                self.ram.copy_within(block..block + 4, 0);
+            }
+        }
+        self.enabled = self.dac;
+        self.length.trigger(odd_step);
+        self.position = 0;
+        // The first fetch comes 3 APU cycles later than a period
+        self.timer = (2048 - self.frequency) * 2 + 6;
+    }
+
+    // While playing, the CPU accesses the byte the channel is at instead of
+    // the addressed one. The DMG only allows it on the cycle of the fetch.
+    fn ram_index(&self, addr: u16) -> Option<usize> {
+        if !self.enabled {
+            Some((addr - 0xff30) as usize)
+        } else if self.cgb || self.fetched {
+            Some(self.position as usize / 2)
+        } else {
+            None
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        self.ram_index(addr).map_or(0xff, |i| self.ram[i])
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) {
+        if let Some(i) = self.ram_index(addr) {
+            self.ram[i] = val;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.volume_code {
+            0 => 0,
+            code => self.sample >> (code - 1),
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8, odd_step: bool) {
+        match reg {
+            0 => {
+                self.dac = val & 0x80 != 0;
+                if !self.dac {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(val as u16),
+            2 => self.volume_code = (val >> 5) & 0x3,
+            3 => self.frequency = self.frequency & 0x700 | val as u16,
+            4 => {
+                self.frequency = self.frequency & 0xff | ((val & 0x07) as u16) << 8;
+                let trigger = val & 0x80 != 0;
+                if !self.length.write_enable(val & 0x40 != 0, trigger, odd_step) {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger(odd_step);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+struct Noise {
+    enabled: bool,
+    nr43: u8,
+    lfsr: u16,
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Noise {
+            enabled: false,
+            nr43: 0,
+            lfsr: 0x7fff,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::default(),
+        }
+    }
+
+    fn period(&self) -> u32 {
+        let divisor = match self.nr43 & 0x07 {
+            0 => 8,
+            x => x as u32 * 16,
+        };
+        divisor << (self.nr43 >> 4)
+    }
+
+    fn tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            // Shifts 14 and 15 get no clock
+            if self.nr43 >> 4 < 14 {
+                let xor = (self.lfsr ^ self.lfsr >> 1) & 1;
+                self.lfsr = self.lfsr >> 1 | xor << 14;
+                if self.nr43 & 0x08 != 0 {
+                    self.lfsr = self.lfsr & !0x40 | xor << 6;
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self, odd_step: bool) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(odd_step);
+        self.envelope.trigger();
+        self.lfsr = 0x7fff;
+        self.timer = self.period();
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8, odd_step: bool) {
+        match reg {
+            1 => self.length.load((val & 0x3f) as u16),
+            2 => {
+                self.envelope.write(val, self.enabled);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.nr43 = val,
+            4 => {
+                let trigger = val & 0x80 != 0;
+                if !self.length.write_enable(val & 0x40 != 0, trigger, odd_step) {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger(odd_step);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// User controls applied on top of NR50 and NR51, channels 0 to 3
+pub struct Mixer {
+    pub muted: [bool; 4],
+    pub solo: [bool; 4], // when any is set, only these channels play
+    pub gain: [f32; 4],
+    pub master: f32,
+    pub mute: bool,
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Mixer {
+            muted: [false; 4],
+            solo: [false; 4],
+            gain: [1.0; 4],
+            master: 1.0,
+            mute: false,
+        }
+    }
+}
+
+impl Mixer {
+    fn channel_gain(&self, channel: usize) -> f32 {
+        let soloing = self.solo.contains(&true);
+        if self.muted[channel] || (soloing && !self.solo[channel]) {
+            0.0
+        } else {
+            self.gain[channel]
+        }
+    }
+
+    pub fn master_gain(&self) -> f32 {
+        if self.mute {
+            0.0
+        } else {
+            self.master
+        }
+    }
+}
+
+// One host sample, each channel panned and scaled by NR50 and the mixer but
+// not by the master volume
+#[derive(Clone, Copy, Default)]
+pub struct Frame {
+    pub channels: [(f32, f32); 4],
+}
+
+impl Frame {
+    pub fn mix(&self) -> (f32, f32) {
+        self.channels
+            .iter()
+            .fold((0.0, 0.0), |(left, right), channel| (left + channel.0, right + channel.1))
+    }
+}
+
+pub struct Apu {
+    pub mixer: Mixer,
+    cgb: bool,
+    power: bool,
+    regs: [u8; 0x30], // last written values, for readback
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    nr50: u8,
+    nr51: u8,
+    frame_step: u8,
+    div_bit: bool,
+
+    sample_rate: u32,
+    phase: u64,
+    step: u64, // host samples per cycle, 16 bits fixed point
+    blips: [Blip; 4],
+    filter: FilterModel,
+    capacitor: Frame,
+    charge: f32, // FilterModel::charge over one host sample
+}
+
+impl Apu {
+    pub fn new(sample_rate: u32, cgb: bool) -> Self {
+        Apu {
+            mixer: Mixer::default(),
+            cgb,
+            power: false,
+            regs: [0; 0x30],
+            square1: Square::new(true),
+            square2: Square::new(false),
+            wave: Wave::new(cgb),
+            noise: Noise::new(),
+            nr50: 0,
+            nr51: 0,
+            frame_step: 0,
+            div_bit: false,
+            sample_rate,
+            phase: 0,
+            step: (sample_rate as u64) << 16,
+            blips: [Blip::new(), Blip::new(), Blip::new(), Blip::new()],
+            filter: FilterModel::Off,
+            capacitor: Frame::default(),
+            charge: 0.0,
+        }
+        .with_filter(if cgb { FilterModel::Cgb } else { FilterModel::Dmg })
+    }
+
+    pub fn with_filter(mut self, filter: FilterModel) -> Self {
+        self.filter = filter;
+        let charge = filter.charge().unwrap_or(0.0);
+        self.charge = charge.powf(CPU_FREQ as f64 / self.sample_rate as f64) as f32;
+        self
+    }
+
+    // Called every T-cycle with DIV, returns a stereo sample at the host rate
+    pub fn tick(&mut self, div: u8) -> Option<Frame> {
+        // The frame sequencer steps when bit 4 of DIV falls, also on DIV resets
+        let div_bit = div & 0x10 != 0;
+        if self.div_bit && !div_bit && self.power {
+            self.step_frame_sequencer();
+        }
+        self.div_bit = div_bit;
+
+        if self.power {
+            self.square1.tick();
+            self.square2.tick();
+            self.wave.tick();
+            self.noise.tick();
+        }
+        let position = self.phase as f64 / ((CPU_FREQ as u64) << 16) as f64;
+        let channels = self.mix();
+        for (blip, channel) in self.blips.iter_mut().zip(channels) {
+            blip.set(channel, position);
+        }
+
+        self.phase += self.step;
+        if self.phase < (CPU_FREQ as u64) << 16 {
+            return None;
+        }
+        self.phase -= (CPU_FREQ as u64) << 16;
+        let mut frame = Frame::default();
+        for (channel, blip) in frame.channels.iter_mut().zip(self.blips.iter_mut()) {
+            *channel = blip.next_sample();
+        }
+        Some(self.high_pass(frame))
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Produce slightly more or fewer samples, to follow the audio device clock
+    pub fn set_rate_ratio(&mut self, ratio: f64) {
+        self.step = (self.sample_rate as f64 * ratio * 65536.0) as u64;
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        if self.frame_step & 1 == 0 {
+            for (enabled, length) in [
+                (&mut self.square1.enabled, &mut self.square1.length),
+                (&mut self.square2.enabled, &mut self.square2.length),
+                (&mut self.wave.enabled, &mut self.wave.length),
+                (&mut self.noise.enabled, &mut self.noise.length),
+            ] {
+                if length.clock() {
+                    *enabled = false;
+                }
+            }
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.square1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) & 7;
+    }
+
+    // Each DAC outputs from -1 to 1, NR51 pans and NR50 scales each side.
+    // The mixer settings scale the channels.
+    fn mix(&self) -> [(f32, f32); 4] {
+        let dac = |enabled: bool, output: u8| {
+            if enabled {
+                output as f32 / 7.5 - 1.0
+            } else {
+                0.0
+            }
+        };
+        let channels = [
+            dac(self.square1.dac_enabled(), self.square1.output()),
+            dac(self.square2.dac_enabled(), self.square2.output()),
+            dac(self.wave.dac, self.wave.output()),
+            dac(self.noise.envelope.dac_enabled(), self.noise.output()),
+        ];
+        let left_vol = ((self.nr50 >> 4) & 0x7) as f32 + 1.0;
+        let right_vol = (self.nr50 & 0x7) as f32 + 1.0;
+        let mut res = [(0.0, 0.0); 4];
+        for (i, channel) in channels.iter().enumerate() {
+            let channel = channel * self.mixer.channel_gain(i) / 32.0;
+            if self.nr51 & (0x10 << i) != 0 {
+                res[i].0 = channel * left_vol;
+            }
+            if self.nr51 & (0x01 << i) != 0 {
+                res[i].1 = channel * right_vol;
+            }
+        }
+        res
+    }
+
+    // Removes the DC offset of the DACs, for each channel so that the stems
+    // add up to the mix
+    fn high_pass(&mut self, mut frame: Frame) -> Frame {
+        if self.filter == FilterModel::Off {
+            return frame;
+        }
+        for (channel, capacitor) in frame.channels.iter_mut().zip(self.capacitor.channels.iter_mut()) {
+            let out = (channel.0 - capacitor.0, channel.1 - capacitor.1);
+            capacitor.0 = channel.0 - out.0 * self.charge;
+            capacitor.1 = channel.1 - out.1 * self.charge;
+            *channel = out;
+        }
+        frame
+    }
+
+    // Registers are cleared and the channels stopped, the wave RAM is kept.
+    // The DMG also keeps the length counters.
+    fn power_off(&mut self) {
+        let lengths = [
+            self.square1.length.counter,
+            self.square2.length.counter,
+            self.wave.length.counter,
+            self.noise.length.counter,
+        ];
+        let ram = self.wave.ram;
+        self.square1 = Square::new(true);
+        self.square2 = Square::new(false);
+        self.wave = Wave::new(self.cgb);
+        self.wave.ram = ram;
+        self.noise = Noise::new();
+        if !self.cgb {
+            self.square1.length.counter = lengths[0];
+            self.square2.length.counter = lengths[1];
+            self.wave.length.counter = lengths[2];
+            self.noise.length.counter = lengths[3];
+        }
+        self.nr50 = 0;
+        self.nr51 = 0;
+        self.regs[..0x16].fill(0);
+    }
+
+    // Writes that bring another APU to the current register state, without
+    // triggering the channels
+    pub fn state_writes(&self) -> Vec<(u16, u8)> {
+        let mut res = vec![(0xff26, (self.power as u8) << 7)];
+        res.extend((0xff30..).zip(self.wave.ram));
+        if self.power {
+            for addr in (0xff10..0xff26).filter(|addr| !matches!(addr, 0xff15 | 0xff1f)) {
+                let val = self.regs[(addr - 0xff10) as usize];
+                let is_nrx4 = matches!(addr, 0xff14 | 0xff19 | 0xff1e | 0xff23);
+                res.push((addr, if is_nrx4 { val & 0x7f } else { val }));
+            }
+        }
+        res
+    }
+
+    // Write-only and unused bits are set by the bus register map
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xff26 => {
+                (self.power as u8) << 7
+                    | (self.noise.enabled as u8) << 3
+                    | (self.wave.enabled as u8) << 2
+                    | (self.square2.enabled as u8) << 1
+                    | self.square1.enabled as u8
+            }
+            0xff30..=0xff3f => self.wave.read_ram(addr),
+            _ => self.regs[(addr - 0xff10) as usize],
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        if !self.power && !matches!(addr, 0xff26 | 0xff30..=0xff3f) {
+            // Only the length counters of the DMG can be written while off
+            if !self.cgb {
+                match addr {
+                    0xff11 => self.square1.length.load((val & 0x3f) as u16),
+                    0xff16 => self.square2.length.load((val & 0x3f) as u16),
+                    0xff1b => self.wave.length.load(val as u16),
+                    0xff20 => self.noise.length.load((val & 0x3f) as u16),
+                    _ => {}
+                }
+            }
+            return;
+        }
+        self.regs[(addr - 0xff10) as usize] = val;
+        let odd_step = self.frame_step & 1 != 0;
+        match addr {
+            0xff10..=0xff14 => self.square1.write(addr - 0xff10, val, odd_step),
+            0xff15..=0xff19 => self.square2.write(addr - 0xff15, val, odd_step),
+            0xff1a..=0xff1e => self.wave.write(addr - 0xff1a, val, odd_step),
+            0xff1f..=0xff23 => self.noise.write(addr - 0xff1f, val, odd_step),
+            0xff24 => self.nr50 = val,
+            0xff25 => self.nr51 = val,
+            0xff26 => {
+                let power = val & 0x80 != 0;
+                if power && !self.power {
+                    self.frame_step = 0;
+                } else if !power && self.power {
+                    self.power_off();
+                }
+                self.power = power;
+            }
+            0xff30..=0xff3f => self.wave.write_ram(addr, val),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run for a number of T-cycles with DIV counting like the timer does
+    fn run(apu: &mut Apu, div: &mut u16, cycles: u32) -> Vec<(f32, f32)> {
+        let mut samples = vec![];
+        for _ in 0..cycles {
+            *div = div.wrapping_add(1);
+            samples.extend(apu.tick((*div >> 8) as u8).map(|frame| frame.mix()));
+        }
+        samples
+    }
+
+    #[test]
+    fn square_frequency_and_sample_rate() {
+        let mut apu = Apu::new(48000, false);
+        let mut div = 0;
+        apu.write(0xff26, 0x80);
+        apu.write(0xff25, 0x11);
+        apu.write(0xff24, 0x77);
+        apu.write(0xff11, 0x80); // 50% duty
+        apu.write(0xff12, 0xf0);
+        // 1048576 / (2048 - 1536) / 8 = 256 Hz
+        apu.write(0xff13, 0x00);
+        apu.write(0xff14, 0x86);
+        let samples = run(&mut apu, &mut div, CPU_FREQ);
+        assert_eq!(samples.len(), 48000);
+        // Skipping the ringing of the first step
+        let rising = samples[100..].windows(2).filter(|w| w[0].0 < 0.0 && w[1].0 >= 0.0).count();
+        assert!((255..=257).contains(&rising), "{rising} periods");
+    }
+
+    #[test]
+    fn length_counter_stops_channel() {
+        let mut apu = Apu::new(48000, false);
+        let mut div = 0;
+        apu.write(0xff26, 0x80);
+        apu.write(0xff21, 0xf0);
+        apu.write(0xff20, 0x3e); // 2 length clocks
+        apu.write(0xff23, 0xc0);
+        assert!(apu.noise.enabled);
+        // Length is clocked at 256 Hz, every 16384 cycles
+        run(&mut apu, &mut div, 16384);
+        assert!(apu.noise.enabled);
+        run(&mut apu, &mut div, 16384);
+        assert!(!apu.noise.enabled);
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut apu = Apu::new(48000, false);
+        let mut div = 0;
+        apu.write(0xff26, 0x80);
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff11, 0xbf); // 1 length clock
+        apu.write(0xff14, 0xc0);
+        apu.write(0xff30, 0x12);
+        assert_eq!(apu.read(0xff26), 0x81);
+        assert_eq!(apu.read(0xff11), 0xbf);
+        run(&mut apu, &mut div, 16384);
+        assert_eq!(apu.read(0xff26), 0x80);
+
+        apu.write(0xff26, 0x00);
+        assert_eq!(apu.read(0xff12), 0);
+        apu.write(0xff12, 0xf0);
+        assert_eq!(apu.read(0xff12), 0);
+        apu.write(0xff31, 0x34);
+        assert_eq!(apu.read(0xff30), 0x12);
+        assert_eq!(apu.read(0xff31), 0x34);
+        // The DMG length counter was written while off
+        apu.write(0xff20, 0x3f);
+        assert_eq!(apu.noise.length.counter, 1);
+    }
+
+    #[test]
+    fn sweep_slides_then_overflows() {
+        let mut apu = Apu::new(48000, false);
+        let mut div = 0;
+        apu.write(0xff26, 0x80);
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff10, 0x11); // every 128 Hz step, up by 1/2
+        apu.write(0xff13, 0x00);
+        apu.write(0xff14, 0x82); // 0x200
+        assert!(apu.square1.enabled);
+        // Sweep steps at 8192 * (3, 7, ...) cycles
+        run(&mut apu, &mut div, 8192 * 3);
+        assert_eq!(apu.square1.frequency, 0x300);
+        run(&mut apu, &mut div, 8192 * 4);
+        assert_eq!(apu.square1.frequency, 0x480);
+        assert!(apu.square1.enabled);
+        // 0x6c0 is applied but 0xa20 overflows the second check
+        run(&mut apu, &mut div, 8192 * 4);
+        assert!(!apu.square1.enabled);
+
+        // Leaving negate mode after a negated calculation stops the channel
+        apu.write(0xff10, 0x19);
+        apu.write(0xff14, 0x82);
+        run(&mut apu, &mut div, 8192 * 4);
+        assert!(apu.square1.enabled);
+        apu.write(0xff10, 0x11);
+        assert!(!apu.square1.enabled);
+    }
+
+    #[test]
+    fn length_extra_clock_and_channel_2_frequency() {
+        let mut apu = Apu::new(48000, false);
+        let mut div = 0;
+        apu.write(0xff26, 0x80);
+        apu.write(0xff17, 0xf0);
+        apu.write(0xff18, 0x34);
+        apu.write(0xff19, 0x87);
+        assert_eq!(apu.square2.frequency, 0x734);
+        // After the first step the next one does not clock the length
+        run(&mut apu, &mut div, 8192);
+        apu.write(0xff16, 0x3f); // length 1
+        apu.write(0xff19, 0x47);
+        assert!(!apu.square2.enabled);
+    }
+
+    #[test]
+    fn zombie_mode_volume() {
+        let mut apu = Apu::new(48000, false);
+        apu.write(0xff26, 0x80);
+        apu.write(0xff12, 0x80);
+        apu.write(0xff14, 0x80);
+        apu.write(0xff12, 0x80); // period 0 and running: +1
+        assert_eq!(apu.square1.envelope.volume, 9);
+        apu.write(0xff12, 0x88); // +1 again, then direction change: 16 - volume
+        assert_eq!(apu.square1.envelope.volume, 6);
+
+        // Full volume decreasing: +2 goes past 15 before the direction change
+        apu.write(0xff12, 0xf1);
+        apu.write(0xff14, 0x80);
+        apu.write(0xff12, 0xf9);
+        assert_eq!(apu.square1.envelope.volume, 15);
+    }
+
+    #[test]
+    fn mixer_solo_and_mute() {
+        let mut apu = Apu::new(48000, false);
+        apu.write(0xff26, 0x80);
+        apu.write(0xff24, 0x77);
+        apu.write(0xff25, 0x12); // channel 1 left, channel 2 right
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff14, 0x80);
+        apu.write(0xff17, 0xf0);
+        apu.write(0xff19, 0x80);
+        let mix = |apu: &Apu| Frame { channels: apu.mix() }.mix();
+        let (left, right) = mix(&apu);
+        assert!(left != 0.0 && right != 0.0);
+
+        apu.mixer.solo[1] = true;
+        assert_eq!(mix(&apu), (0.0, right));
+        apu.mixer.gain[1] = 0.5;
+        assert_eq!(mix(&apu), (0.0, right * 0.5));
+        apu.mixer.mute = true;
+        assert_eq!(apu.mixer.master_gain(), 0.0);
+    }
+
+    #[test]
+    fn high_square_does_not_alias() {
+        let mut apu = Apu::new(48000, false);
+        let mut div = 0;
+        apu.write(0xff26, 0x80);
+        apu.write(0xff25, 0x11);
+        apu.write(0xff24, 0x77);
+        apu.write(0xff11, 0x80);
+        apu.write(0xff12, 0xf0);
+        // 65536 Hz, only its average can be heard
+        apu.write(0xff13, 0xfe);
+        apu.write(0xff14, 0x87);
+        let samples = run(&mut apu, &mut div, CPU_FREQ / 2);
+        let tail = &samples[samples.len() / 2..];
+        let mean = tail.iter().map(|s| s.0).sum::<f32>() / tail.len() as f32;
+        let rms = (tail.iter().map(|s| (s.0 - mean).powi(2)).sum::<f32>() / tail.len() as f32).sqrt();
+        assert!(rms < 0.001, "rms {rms}");
+    }
+
+    #[test]
+    fn wave_ram_access_while_playing() {
+        for cgb in [false, true] {
+            let mut apu = Apu::new(48000, cgb);
+            let mut div = 0;
+            apu.write(0xff26, 0x80);
+            for i in 0..16 {
+                apu.write(0xff30 + i, i as u8 * 0x11);
+            }
+            apu.write(0xff1a, 0x80);
+            apu.write(0xff1d, 0x00); // period of 4096 cycles
+            apu.write(0xff1e, 0x80);
+            // Fetch of the second nibble, then of the third one
+            run(&mut apu, &mut div, 4096 + 6);
+            assert_eq!(apu.read(0xff3f), 0x00);
+            run(&mut apu, &mut div, 1);
+            assert_eq!(apu.read(0xff3f), if cgb { 0x00 } else { 0xff });
+            run(&mut apu, &mut div, 4095);
+            assert_eq!(apu.read(0xff30), 0x11);
+            apu.write(0xff3a, 0x42);
+            assert_eq!(apu.wave.ram[1], 0x42);
+            assert_eq!(apu.wave.ram[10], 0xaa);
+        }
+    }
+
+    #[test]
+    fn dmg_wave_retrigger_corrupts_ram() {
+        let mut apu = Apu::new(48000, false);
+        let mut div = 0;
+        apu.write(0xff26, 0x80);
+        for i in 0..16 {
+            apu.write(0xff30 + i, i as u8 * 0x11);
+        }
+        apu.write(0xff1a, 0x80);
+        apu.write(0xff1d, 0x00);
+        apu.write(0xff1e, 0x80);
+        // Position 11 is next to be fetched, from byte 5 in the second block
+        run(&mut apu, &mut div, 4096 * 10 + 6 - 1);
+        apu.write(0xff1e, 0x80);
+        assert_eq!(apu.wave.ram[..5], [0x44, 0x55, 0x66, 0x77, 0x44]);
+    }
+}
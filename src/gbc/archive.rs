@@ -0,0 +1,128 @@
+// ROM files, possibly compressed in a zip or gzip archive. Archives are told
+// apart by their magic bytes, not their extension.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+// Bigger than any cartridge, stops corrupted archives from filling the memory
+const MAX_ROM_SIZE: u64 = 0x1000000;
+
+const ROM_EXTENSIONS: [&str; 3] = ["gb", "gbc", "sgb"];
+
+// Returns the ROM and the path it would have unpacked next to the archive,
+// which names the save file
+pub fn read(path: &str, entry: Option<&str>) -> Result<(Vec<u8>, PathBuf)> {
+    let path = Path::new(path);
+    let mut file = File::open(path).with_context(|| format!("Error opening {}", path.display()))?;
+    let mut magic = [0; 4];
+    let len = file.read(&mut magic)?;
+    drop(file);
+    match magic[..len] {
+        [b'P', b'K', 3, 4] => read_zip(path, entry),
+        [0x1f, 0x8b, ..] => read_gzip(path),
+        _ => {
+            if entry.is_some() {
+                bail!("{} is not a zip archive", path.display());
+            }
+            Ok((fs::read(path)?, path.to_owned()))
+        }
+    }
+}
+
+// The chosen entry, or the first one with a ROM extension
+fn read_zip(path: &Path, entry: Option<&str>) -> Result<(Vec<u8>, PathBuf)> {
+    let mut archive = ZipArchive::new(File::open(path)?)
+        .with_context(|| format!("Invalid zip archive {}", path.display()))?;
+    let index = match entry {
+        Some(name) => archive.index_for_name(name).with_context(|| {
+            format!("No entry {name} in {}", path.display())
+        })?,
+        None => (0..archive.len())
+            .find(|&i| archive.name_for_index(i).is_some_and(is_rom_name))
+            .with_context(|| format!("No ROM in {}", path.display()))?,
+    };
+    let mut file = archive.by_index(index)?;
+    let name = Path::new(file.name()).file_name().context("Invalid entry name")?.to_owned();
+    println!("Loading {} from the archive", file.name());
+    let data = read_limited(&mut file)?;
+    Ok((data, path.with_file_name(name)))
+}
+
+// The original name is in the header, or the archive name without .gz
+fn read_gzip(path: &Path) -> Result<(Vec<u8>, PathBuf)> {
+    let mut decoder = GzDecoder::new(File::open(path)?);
+    let data = read_limited(&mut decoder)
+        .with_context(|| format!("Invalid gzip archive {}", path.display()))?;
+    let name = decoder
+        .header()
+        .and_then(|header| header.filename())
+        .and_then(|name| Path::new(std::str::from_utf8(name).ok()?).file_name());
+    let rom_path = match name {
+        Some(name) => path.with_file_name(name),
+        None => path.with_extension(""),
+    };
+    Ok((data, rom_path))
+}
+
+fn read_limited(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    reader.take(MAX_ROM_SIZE + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > MAX_ROM_SIZE {
+        bail!("The ROM is bigger than {} MiB", MAX_ROM_SIZE >> 20);
+    }
+    Ok(data)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ROM_EXTENSIONS.iter().any(|rom| ext.eq_ignore_ascii_case(rom)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_dir::TestDir;
+    use flate2::write::GzEncoder;
+    use flate2::{Compression, GzBuilder};
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    #[test]
+    fn archives_name_the_rom_after_their_entry() {
+        let dir = TestDir::new("archive");
+
+        let zip_path = dir.join("games.zip");
+        let mut zip = ZipWriter::new(File::create(&zip_path).unwrap());
+        zip.start_file("readme.txt", SimpleFileOptions::default()).unwrap();
+        zip.write_all(b"hello").unwrap();
+        for (name, data) in [("roms/first.GB", [1]), ("second.gbc", [2])] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(&data).unwrap();
+        }
+        zip.finish().unwrap();
+        let zip_str = zip_path.to_str().unwrap();
+        assert_eq!(read(zip_str, None).unwrap(), (vec![1], dir.join("first.GB")));
+        assert_eq!(read(zip_str, Some("second.gbc")).unwrap(), (vec![2], dir.join("second.gbc")));
+        assert!(read(zip_str, Some("third.gb")).is_err());
+
+        let gz_path = dir.join("game.gb.gz");
+        let mut gz = GzEncoder::new(File::create(&gz_path).unwrap(), Compression::default());
+        gz.write_all(&[3]).unwrap();
+        gz.finish().unwrap();
+        assert_eq!(read(gz_path.to_str().unwrap(), None).unwrap(), (vec![3], dir.join("game.gb")));
+
+        let gz = GzBuilder::new().filename("named.gbc");
+        let mut gz = gz.write(File::create(&gz_path).unwrap(), Compression::default());
+        gz.write_all(&[4]).unwrap();
+        gz.finish().unwrap();
+        assert_eq!(read(gz_path.to_str().unwrap(), None).unwrap(), (vec![4], dir.join("named.gbc")));
+    }
+}
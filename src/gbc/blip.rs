@@ -0,0 +1,87 @@
+// Band-limited synthesis in the way of blip_buf: the channels only report
+// when their level changes, and each change is added to the output as a
+// windowed sinc step at its exact position between two host samples. Square
+// waves far above the host rate then come out without aliasing.
+
+use std::f64::consts::PI;
+use std::sync::OnceLock;
+
+// Kernel length in host samples, also the latency
+const WIDTH: usize = 16;
+// Sub-sample positions of a step
+const PHASES: usize = 512;
+// Fraction of the Nyquist frequency kept
+const CUTOFF: f64 = 0.9;
+
+const BUFFER_SIZE: usize = 32;
+
+type Kernel = [[f32; WIDTH]; PHASES];
+
+// Impulse responses, the buffer is integrated when read so they add steps
+fn kernel() -> &'static Kernel {
+    static KERNEL: OnceLock<Kernel> = OnceLock::new();
+    KERNEL.get_or_init(|| {
+        let mut kernel = [[0.0; WIDTH]; PHASES];
+        for (phase, taps) in kernel.iter_mut().enumerate() {
+            let offset = phase as f64 / PHASES as f64;
+            let mut sum = 0.0;
+            let mut values = [0.0; WIDTH];
+            for (i, value) in values.iter_mut().enumerate() {
+                let x = i as f64 - (WIDTH / 2 - 1) as f64 - offset;
+                let sinc = if x == 0.0 { 1.0 } else { (PI * CUTOFF * x).sin() / (PI * CUTOFF * x) };
+                // Blackman window over the kernel
+                let w = (x + (WIDTH / 2) as f64) / WIDTH as f64;
+                let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+                *value = sinc * window;
+                sum += *value;
+            }
+            // Each step must add exactly its height
+            for (tap, value) in taps.iter_mut().zip(values) {
+                *tap = (value / sum) as f32;
+            }
+        }
+        kernel
+    })
+}
+
+// Stereo, the level is held between steps
+pub struct Blip {
+    buffer: [(f32, f32); BUFFER_SIZE],
+    head: usize, // next host sample
+    level: (f32, f32), // input level after the last step
+    output: (f32, f32), // integral of the buffer
+}
+
+impl Blip {
+    pub fn new() -> Self {
+        Blip {
+            buffer: [(0.0, 0.0); BUFFER_SIZE],
+            head: 0,
+            level: (0.0, 0.0),
+            output: (0.0, 0.0),
+        }
+    }
+
+    // Sets the level at a position from 0 to 1 before the next host sample
+    pub fn set(&mut self, level: (f32, f32), position: f64) {
+        let delta = (level.0 - self.level.0, level.1 - self.level.1);
+        if delta == (0.0, 0.0) {
+            return;
+        }
+        self.level = level;
+        let phase = ((position * PHASES as f64) as usize).min(PHASES - 1);
+        for (i, tap) in kernel()[phase].iter().enumerate() {
+            let sample = &mut self.buffer[(self.head + i) % BUFFER_SIZE];
+            sample.0 += delta.0 * tap;
+            sample.1 += delta.1 * tap;
+        }
+    }
+
+    pub fn next_sample(&mut self) -> (f32, f32) {
+        let sample = std::mem::take(&mut self.buffer[self.head]);
+        self.head = (self.head + 1) % BUFFER_SIZE;
+        self.output.0 += sample.0;
+        self.output.1 += sample.1;
+        self.output
+    }
+}
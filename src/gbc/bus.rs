@@ -1,13 +1,18 @@
-use anyhow::Result;
+use anyhow::{Result, bail};
 use super::ppu::Ppu;
 use super::memory::Ram;
 use super::sound::Sound;
 use super::timer::Timer;
 use super::cartridge::Cartridge;
 use super::input::Joypad;
+use super::io::{self, IoRegister};
+use super::model::Model;
+use super::sgb::Sgb;
+use super::Config;
 
 
 pub struct Bus{
+    pub model: Model,
     pub ppu: Ppu,
     pub timer: Timer,
     pub cartridge: Box<dyn Cartridge>,
@@ -15,7 +20,10 @@ pub struct Bus{
     pub requested_interrupts: u8,
     pub joypad: Joypad,
     pub sound: Sound,
+    pub sgb: Option<Sgb>,
     ram: Ram,
+    io: [u8; 0x80], // registers without a dedicated component
+    boot_rom: Option<Vec<u8>>,
 }
 
 pub trait Busable {
@@ -29,9 +37,46 @@ pub const TIMER: u8 = 0x04;
 pub const SERIAL: u8 = 0x08;
 pub const JOYPAD: u8 = 0x10;
 
+// I/O state left by the DMG boot ROM, NR52 first so the APU accepts writes
+const DMG_POST_BOOT_IO: [(u16, u8); 32] = [
+    (0xff26, 0xf1),
+    (0xff00, 0xcf),
+    (0xff01, 0x00),
+    (0xff02, 0x7e),
+    (0xff05, 0x00),
+    (0xff06, 0x00),
+    (0xff07, 0xf8),
+    (0xff0f, 0xe1),
+    (0xff10, 0x80),
+    (0xff11, 0xbf),
+    (0xff12, 0xf3),
+    (0xff13, 0xff),
+    (0xff14, 0x3f), // 0xbf without the trigger bit
+    (0xff16, 0x3f),
+    (0xff17, 0x00),
+    (0xff18, 0xff),
+    (0xff19, 0x3f),
+    (0xff1a, 0x7f),
+    (0xff1b, 0xff),
+    (0xff1c, 0x9f),
+    (0xff1d, 0xff),
+    (0xff1e, 0x3f),
+    (0xff20, 0xff),
+    (0xff21, 0x00),
+    (0xff22, 0x00),
+    (0xff23, 0x3f),
+    (0xff24, 0x77),
+    (0xff25, 0xf3),
+    (0xff40, 0x91),
+    (0xff47, 0xfc),
+    (0xff48, 0xff),
+    (0xff49, 0xff),
+];
+
 impl Busable for Bus {
     fn read(&self, addr: u16) -> u8{
         match addr {
+            x if self.is_boot_rom(x) => self.boot_rom.as_ref().unwrap()[addr as usize],
             x if x < 0x8000 => self.cartridge.read(addr),
             x if x < 0xa000 => self.ppu.read(addr),
             x if x < 0xc000 => self.cartridge.read(addr),
@@ -41,7 +86,101 @@ impl Busable for Bus {
             x if x <= 0xfeff => 0,
             x if (0xff80..=0xfffe).contains(&x) => self.ram.read(addr),
             0xffff => self.enabled_interrupts,
-            0xff00 => {self.joypad.read()} // joypad
+            _ => {
+                let reg = io::register(addr);
+                if !self.is_mapped(reg) {
+                    return 0xff; // open bus
+                }
+                self.read_io(addr) | reg.read_ones
+            }
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8){
+        match addr {
+            x if x < 0x8000 => self.cartridge.write(addr, value),
+            x if x < 0xa000 => self.ppu.write(addr, value),
+            x if x < 0xc000 => self.cartridge.write(addr, value),
+            x if x < 0xe000 => self.ram.write(addr, value),
+            x if x < 0xFE00 => self.ram.write(addr - 0x2000, value),
+            x if x < 0xfea0 => self.ppu.write(addr, value),
+            x if x <= 0xfeff => {},
+            x if (0xff80..=0xfffe).contains(&x) => self.ram.write(addr, value),
+            0xffff => self.enabled_interrupts = value,
+            _ => {
+                let reg = io::register(addr);
+                if self.is_mapped(reg) && reg.write_mask != 0 {
+                    self.write_io(addr, value & reg.write_mask);
+                }
+            }
+        };
+    }
+}
+
+impl Bus {
+    pub fn new(cartridge: Box<dyn Cartridge>, model: Model, config: &Config) -> Result<Self> {
+        Ok(Bus {
+            model,
+            ppu: Ppu::new(model),
+            ram: Ram::new(),
+            timer: Timer::new(),
+            cartridge,
+            enabled_interrupts: 0x0,
+            requested_interrupts: 0x0,
+            sound: Sound::new(model.is_cgb(), config)?,
+            joypad: Joypad::new(),
+            sgb: None,
+            io: [0; 0x80],
+            boot_rom: None,
+        })
+    }
+
+    // Map a DMG (256 bytes) or CGB (2304 bytes) boot ROM until 0xff50 is written
+    pub fn load_boot_rom(&mut self, data: Vec<u8>) -> Result<()> {
+        if data.len() != 0x100 && data.len() != 0x900 {
+            bail!("Invalid boot ROM size: {} bytes", data.len());
+        }
+        self.boot_rom = Some(data);
+        Ok(())
+    }
+
+    pub fn enable_sgb(&mut self) {
+        self.sgb = Some(Sgb::new());
+        self.joypad.enable_sgb();
+    }
+
+    // Put the I/O registers in the state the boot ROM leaves them
+    pub fn skip_boot(&mut self, gbc_cartridge: bool) {
+        for (addr, value) in DMG_POST_BOOT_IO {
+            self.write(addr, value);
+        }
+        self.io[0x46] = 0xff; // DMA, not written to avoid a transfer
+        self.timer.set_div(match self.model {
+            Model::Dmg | Model::Mgb => 0xab,
+            Model::Sgb => 0xd8,
+            Model::Cgb | Model::Agb => 0x26,
+        });
+        if self.model.is_cgb() && !gbc_cartridge {
+            // DMG games keep the DMG sprite priority
+            self.write(0xff6c, 0x01);
+        }
+        self.enabled_interrupts = 0x00;
+    }
+
+    fn is_boot_rom(&self, addr: u16) -> bool {
+        match &self.boot_rom {
+            Some(rom) => addr < 0x100 || ((0x200..0x900).contains(&addr) && (addr as usize) < rom.len()),
+            None => false,
+        }
+    }
+
+    fn is_mapped(&self, reg: &IoRegister) -> bool {
+        reg.mapped && (!reg.cgb_only || self.model.is_cgb())
+    }
+
+    fn read_io(&self, addr: u16) -> u8 {
+        match addr {
+            0xff00 => self.joypad.read(),
             0xff04 => self.timer.get_div(),
             0xff05 => self.timer.get_tima(),
             0xff06 => self.timer.get_tma(),
@@ -53,36 +192,26 @@ impl Busable for Bus {
             0xff43 => self.ppu.get_scx(),
             0xff44 => self.ppu.get_ly(),
             0xff45 => self.ppu.get_lyc(),
-            0xff4a => self.ppu.get_wy(),
-            0xff4b => self.ppu.get_wx(),
-            0xff46 => 0, // dma
             0xff47 => self.ppu.get_bgp(),
             0xff48 => self.ppu.get_obp0(),
             0xff49 => self.ppu.get_obp1(),
-            0xff4d => 0, //cgb Key1
-            0xff56 => 0, //cgb RP
-            0xff68 => 0, // cgb palette
-            0xff7f => 0, //empty
-            0xff01 => 0, // serial
-            0xff02 => 0, // serial
-            x if (0xff10..0xff40).contains(&x) => self.sound.read(addr), // sound
-            _ => panic!("Illegal read at {addr:#x}")
+            0xff4a => self.ppu.get_wy(),
+            0xff4b => self.ppu.get_wx(),
+            0xff6c => self.ppu.get_opri(),
+            x if (0xff10..0xff40).contains(&x) => self.sound.read(addr),
+            _ => self.io[(addr - 0xff00) as usize],
         }
     }
 
-    fn write(&mut self, addr: u16, value: u8){
+    fn write_io(&mut self, addr: u16, value: u8) {
         match addr {
-            x if x < 0x8000 => self.cartridge.write(addr, value),
-            x if x < 0xa000 => self.ppu.write(addr, value),
-            x if x < 0xc000 => self.cartridge.write(addr, value),
-            x if x < 0xe000 => self.ram.write(addr, value),
-            x if x < 0xFE00 => self.ram.write(addr - 0x2000, value),
-            x if x < 0xfea0 => self.ppu.write(addr, value),
-            x if x <= 0xfeff => {},
-            x if (0xff80..=0xfffe).contains(&x) => self.ram.write(addr, value),
-            0xffff => self.enabled_interrupts = value,
-            0xff00 => {self.joypad.write(value)}, // joypad
-            0xff04 => self.timer.reset_div(), 
+            0xff00 => {
+                if let (Some(packet), Some(sgb)) = (self.joypad.write(value), &mut self.sgb) {
+                    sgb.receive(packet);
+                    self.joypad.set_players(sgb.players());
+                }
+            }
+            0xff04 => self.timer.reset_div(),
             0xff05 => self.timer.set_tima(value),
             0xff06 => self.timer.set_tma(value),
             0xff07 => self.timer.set_tac(value),
@@ -91,43 +220,26 @@ impl Busable for Bus {
             0xff41 => self.ppu.set_lcds(value),
             0xff42 => self.ppu.set_scy(value),
             0xff43 => self.ppu.set_scx(value),
-            0xff44 => self.ppu.set_ly(value),
             0xff45 => self.ppu.set_lyc(value),
-            0xff4a => self.ppu.set_wy(value),
-            0xff4b => self.ppu.set_wx(value),
-            0xff46 => self.dma(value), // dma
+            0xff46 => {
+                self.io[0x46] = value;
+                self.dma(value);
+            }
             0xff47 => self.ppu.set_bgp(value),
             0xff48 => self.ppu.set_obp0(value),
             0xff49 => self.ppu.set_obp1(value),
-            0xff4d => {}, //cgb Key1
-            0xff56 => {}, //cgb RP
-            0xff68 => {}, // cgb palette
-            0xff69 => {}, // cgb palette
-            0xff6a => {}, // cgb palette
-            0xff6b => {}, // cgb palette
-            0xff4f => {}, // cgb bank
-            0xff7f => {}, //empty
-            0xff01 => {}, // serial
-            0xff02 => {}, // serial
-            x if (0xff10..0xff40).contains(&x) => self.sound.write(addr, value), // sound
-            _ => panic!("Illegal write at {addr:#x}")
-        };
+            0xff4a => self.ppu.set_wy(value),
+            0xff4b => self.ppu.set_wx(value),
+            0xff6c => self.ppu.set_opri(value),
+            0xff50 => {
+                if value & 1 != 0 {
+                    self.boot_rom = None;
+                }
+            }
+            x if (0xff10..0xff40).contains(&x) => self.sound.write(addr, value),
+            _ => self.io[(addr - 0xff00) as usize] = value,
+        }
     }
-}
-
-impl Bus {
-    pub fn new(cartridge: Box<dyn Cartridge>) -> Result<Self> {
-        Ok(Bus {
-            ppu: Ppu::new(),
-            ram: Ram::new(),
-            timer: Timer::new(),
-            cartridge,
-            enabled_interrupts: 0x0,
-            requested_interrupts: 0x0,
-            sound: Sound::new()?,
-            joypad: Joypad::new(),
-        })
-    } 
 
     pub fn write16(&mut self, addr: u16, value: u16){
         self.write(addr, (value & 0xff) as u8);
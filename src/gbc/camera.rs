@@ -0,0 +1,92 @@
+// Pocket Camera sensor and image processing. There is no real sensor, the
+// captured scene is a fixed test pattern that still goes through the exposure
+// and dithering registers so the camera menus behave.
+
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = 112;
+
+pub struct Camera {
+    regs: [u8; 0x36],
+    busy_cycles: u32,
+}
+
+impl Camera {
+    pub fn new() -> Self {
+        Camera {
+            regs: [0; 0x36],
+            busy_cycles: 0,
+        }
+    }
+
+    // Only the first register can be read back, bit 0 is set during a capture
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr & 0x7f {
+            0 => self.regs[0] & 0x06 | (self.busy_cycles > 0) as u8,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        let reg = (addr & 0x7f) as usize;
+        if reg >= self.regs.len() {
+            return;
+        }
+        if reg == 0 {
+            if val & 1 != 0 && self.busy_cycles == 0 {
+                self.busy_cycles = self.capture_cycles();
+            } else if val & 1 == 0 {
+                self.busy_cycles = 0;
+            }
+            self.regs[0] = val & 0x07;
+        } else {
+            self.regs[reg] = val;
+        }
+    }
+
+    // Returns true when a capture just finished and the image must be stored
+    pub fn tick(&mut self) -> bool {
+        if self.busy_cycles == 0 {
+            return false;
+        }
+        self.busy_cycles -= 1;
+        self.busy_cycles == 0
+    }
+
+    // In T-cycles, the sensor is clocked at a quarter of the CPU
+    fn capture_cycles(&self) -> u32 {
+        let exposure = u16::from_be_bytes([self.regs[2], self.regs[3]]) as u32;
+        let n = if self.regs[1] & 0x80 != 0 { 0 } else { 512 };
+        (32446 + n + 16 * exposure) * 4
+    }
+
+    // The image as 16x14 tiles of 2 bits per pixel, like it lands in RAM
+    pub fn image(&self) -> [u8; WIDTH * HEIGHT / 4] {
+        let exposure = u16::from_be_bytes([self.regs[2], self.regs[3]]) as u32;
+        let mut res = [0; WIDTH * HEIGHT / 4];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                // Diagonal gradient, brighter with a longer exposure
+                let scene = ((x + y) * 255 / (WIDTH + HEIGHT - 2)) as u32;
+                let value = (scene * exposure / 0x1000).min(255) as u8;
+                let color = self.dither(x, y, value);
+                let tile = (y / 8) * (WIDTH / 8) + x / 8;
+                let index = tile * 16 + (y % 8) * 2;
+                let bit = 7 - (x % 8);
+                res[index] |= (color & 1) << bit;
+                res[index + 1] |= (color >> 1) << bit;
+            }
+        }
+        res
+    }
+
+    // Each 4x4 matrix cell has 3 thresholds for the 4 shades
+    fn dither(&self, x: usize, y: usize, value: u8) -> u8 {
+        let cell = 6 + ((y % 4) * 4 + x % 4) * 3;
+        let thresholds = &self.regs[cell..cell + 3];
+        let value = if self.regs[5] & 0x80 != 0 { !value } else { value };
+        match thresholds.iter().position(|&t| value < t) {
+            Some(i) => 3 - i as u8,
+            None => 0,
+        }
+    }
+}
@@ -1,41 +1,85 @@
 use anyhow::{bail, Context, Result};
 use num_enum::TryFromPrimitive;
-use time::{OffsetDateTime, Duration};
+use time::OffsetDateTime;
 use std::convert::TryFrom;
-use std::fs::{File, OpenOptions};
-use std::io::{prelude::*, SeekFrom};
+use std::fs;
 use std::path::PathBuf;
-use std::{str, slice};
+use std::{slice, str};
+
+use super::archive;
+use super::camera::Camera;
+use super::eeprom::Eeprom;
+use super::gbs::{is_gbs, GbsHeader, Tracks, RESTART_ADDR, TRACK_ADDR};
+use super::rtc::{ClockSource, Rtc};
+use super::save::BatterySave;
+use super::Config;
 
 pub trait Cartridge {
     fn read(&self, addr: u16) -> u8;
     fn write(&mut self, addr: u16, val: u8);
+    // Called every cycle, for cartridges with a clock
+    fn tick(&mut self) {}
+    fn rtc(&mut self) -> Option<&mut Rtc> {
+        None
+    }
+    // Accelerometer input, from -1 to 1 with x to the right and y down
+    fn set_tilt(&mut self, _x: f32, _y: f32) {}
+    // Track selection of GBS music files
+    fn tracks(&mut self) -> Option<&mut Tracks> {
+        None
+    }
+    // Write battery backed RAM to disk if it changed
+    fn save(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
 
-pub fn load_rom(path: &str) -> Result<Box<dyn Cartridge>> {
-    let mut rom = Rom::new(path)?;
+// Cartridge header fields needed outside of the mapper
+pub struct Header {
+    pub gbc: bool,
+    pub title_bytes: [u8; 16],
+    pub nintendo: bool, // licensee, the CGB boot ROM only colorizes those games
+    pub sgb: bool,
+}
+
+pub fn load_rom(path: &str, config: &Config) -> Result<(Box<dyn Cartridge>, Header)> {
+    let (data, rom_path) = archive::read(path, config.zip_entry.as_deref())?;
     println!("Loading {path} ...");
-    let title = rom.get_title()?;
-    println!("Title: {title}");
+    if is_gbs(&data) {
+        return load_gbs(&data);
+    }
+    let mut rom = Rom::from_data(data, rom_path, config)?;
+    let clock = config.clock;
+    for warning in rom.validate() {
+        eprintln!("Warning: {warning}");
+    }
+    println!("Title: {}", rom.get_title());
 
     let gbc = rom.is_gbc();
     println!("Game Boy Color mode: {gbc}");
+    let mut title_bytes = [0; 16];
+    title_bytes.copy_from_slice(rom.read_range(0x134, 0x144)?);
+    let nintendo = rom.is_nintendo_licensee();
+    let sgb = rom.is_sgb();
+    println!("Super Game Boy functions: {sgb}");
 
-    println!("External RAM size : {}KiB", rom.get_ram_size()? / 0x400);
+    println!("External RAM size : {}KiB", rom.get_ram_size() / 0x400);
 
-    println!("ROM size : {}KiB", rom.get_rom_size()?);
+    println!("ROM size : {}KiB", rom.get_rom_size());
 
     let mbc_type = rom.get_mbc_type()?;
     let res: Box<dyn Cartridge> = match mbc_type {
-        MbcType::NRom => {
+        MbcType::NRom | MbcType::RomRam | MbcType::RomRamBattery => {
             println!("ROM without MBC");
-            Box::new(NRom::new(&mut rom)?)
+            Box::new(NRom::new(&mut rom, matches!(mbc_type, MbcType::RomRamBattery))?)
         }
         MbcType::Mbc1 | MbcType::Mbc1Ram | MbcType::Mbc1RamBattery => {
-            println!("Mapper is MBC1");
+            let multicart = config.multicart.unwrap_or_else(|| rom.is_mbc1_multicart());
+            println!("Mapper is MBC1{}", if multicart { "M (multicart)" } else { "" });
             Box::new(MBC1::new(
                 &mut rom,
                 matches!(mbc_type, MbcType::Mbc1RamBattery),
+                multicart,
             )?)
         }
         MbcType::Mbc2 | MbcType::Mbc2Battery => {
@@ -50,19 +94,121 @@ pub fn load_rom(path: &str) -> Result<Box<dyn Cartridge>> {
             Box::new(MBC3::new(
                 &mut rom,
                 matches!(mbc_type, MbcType::Mbc3RamBattery | MbcType::Mbc3TimerRamBattery | MbcType::Mbc3TimerBattery),
+                matches!(mbc_type, MbcType::Mbc3TimerRamBattery | MbcType::Mbc3TimerBattery).then_some(clock),
             )?)
         }
+        MbcType::Mmm01 | MbcType::Mmm01Ram | MbcType::Mmm01RamBattery => {
+            println!("Mapper is MMM01");
+            Box::new(MMM01::new(
+                &mut rom,
+                matches!(mbc_type, MbcType::Mmm01RamBattery),
+            )?)
+        }
+        MbcType::Mbc6 | MbcType::Mbc6All => {
+            println!("Mapper is MBC6");
+            Box::new(MBC6::new(&mut rom, true)?)
+        }
+        MbcType::Mbc7SensorRumbleRamBattery => {
+            println!("Mapper is MBC7");
+            Box::new(MBC7::new(&mut rom)?)
+        }
+        MbcType::PocketCamera => {
+            println!("Mapper is Pocket Camera");
+            Box::new(PocketCamera::new(&mut rom)?)
+        }
+        MbcType::Tama5 => {
+            println!("Mapper is TAMA5");
+            Box::new(Tama5::new(&mut rom)?)
+        }
+        MbcType::HuC3 => {
+            println!("Mapper is HuC3");
+            Box::new(HuC3::new(&mut rom)?)
+        }
+        MbcType::HuC1RamBattery => {
+            println!("Mapper is HuC1");
+            Box::new(HuC1::new(&mut rom)?)
+        }
         _ => {
             bail!("Unsuported MBC : {mbc_type:?}");
         }
     };
     println!("Rom loaded !");
-    Ok(res)
+    Ok((res, Header { gbc, title_bytes, nintendo, sgb }))
+}
+
+fn load_gbs(data: &[u8]) -> Result<(Box<dyn Cartridge>, Header)> {
+    let header = GbsHeader::parse(data)?;
+    println!("GBS music: {}", header.title);
+    println!("Author: {}", header.author);
+    println!("Copyright: {}", header.copyright);
+    println!("Track {}/{}", header.first_track + 1, header.tracks);
+    let rom = header.image(data);
+    let mut title_bytes = [0; 16];
+    title_bytes.copy_from_slice(&rom[0x134..0x144]);
+    let gbs = Gbs {
+        rom,
+        bank: 1,
+        ram: [0; 0x2000],
+        tracks: Tracks {
+            count: header.tracks,
+            current: header.first_track,
+            restart: false,
+        },
+    };
+    let header = Header {
+        gbc: false,
+        title_bytes,
+        nintendo: false,
+        sgb: false,
+    };
+    Ok((Box::new(gbs), header))
+}
+
+// GBS image built by gbs::GbsHeader::image, with the track selection read by
+// its player. Writes to 0x2000-0x3fff select the bank, like most rips expect.
+struct Gbs {
+    rom: Vec<u8>,
+    bank: usize,
+    ram: [u8; 0x2000],
+    tracks: Tracks,
+}
+
+impl Cartridge for Gbs {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            TRACK_ADDR => self.tracks.current,
+            RESTART_ADDR => self.tracks.restart as u8,
+            x if x < 0x4000 => self.rom[addr as usize],
+            x if x < 0x8000 => {
+                let banks = self.rom.len() / 0x4000;
+                self.rom[(self.bank % banks) * 0x4000 + addr as usize - 0x4000]
+            }
+            x if (0xa000..0xc000).contains(&x) => self.ram[addr as usize - 0xa000],
+            _ => panic!("Illegal cartridge read at {addr:#x}"),
+        }
+    }
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            // The player starts the track over
+            RESTART_ADDR => {
+                self.tracks.restart = false;
+                self.bank = 1;
+            }
+            x if (0x2000..0x4000).contains(&x) => self.bank = (val as usize).max(1),
+            x if (0xa000..0xc000).contains(&x) => self.ram[addr as usize - 0xa000] = val,
+            _ => {}
+        }
+    }
+
+    fn tracks(&mut self) -> Option<&mut Tracks> {
+        Some(&mut self.tracks)
+    }
 }
 
 struct NRom {
     banks: [u8; 0x8000],
     ram: Vec<u8>,
+    save: Option<BatterySave>,
 }
 
 impl Cartridge for NRom {
@@ -81,144 +227,126 @@ impl Cartridge for NRom {
                 let index = addr as usize - 0xa000;
                 if index < self.ram.len() {
                     self.ram[index] = val;
+                    mark_dirty(&mut self.save);
                 }
             }
             _ => {} // usually select MBC, but noop for NRom
         };
     }
+
+    fn save(&mut self) -> Result<()> {
+        match &mut self.save {
+            Some(save) => save.flush(&self.ram),
+            None => Ok(()),
+        }
+    }
 }
 
 impl NRom {
-    pub fn new(rom: &mut Rom) -> Result<Self> {
-        let rom_data = rom.read_range(0, 0x8000)?;
-        let slice = &rom_data[..0x8000];
-        let ram_size = rom.get_ram_size()?;
-        let mut res = NRom {
-            banks: [0; 0x8000],
-            ram: vec![0; ram_size],
+    pub fn new(rom: &mut Rom, battery: bool) -> Result<Self> {
+        // Smaller ROMs are padded like an unconnected bus, bigger ones cut
+        let len = rom.get_data_len().min(0x8000);
+        let mut banks = [0xff; 0x8000];
+        banks[..len].copy_from_slice(rom.read_range(0, len)?);
+        let mut ram = vec![0; rom.get_ram_size().min(0x2000)];
+        let save = if battery {
+            let (save, data) = BatterySave::open(rom.save_path(), &rom.legacy_save_path())?;
+            if let Some(data) = data {
+                let len = data.len().min(ram.len());
+                ram[..len].copy_from_slice(&data[..len]);
+            }
+            Some(save)
+        } else {
+            None
         };
-        res.banks.copy_from_slice(slice);
-        Ok(res)
+        Ok(NRom { banks, ram, save })
+    }
+}
+
+impl Drop for NRom {
+    fn drop(&mut self) {
+        report_save(self.save());
     }
 }
-// TODO: support bundle cartriges
 struct MBC1 {
     banks: Vec<[u8; 0x4000]>,
     ram: Vec<[u8; 0x2000]>,
-    ram_file: Option<File>,
+    save: Option<BatterySave>,
     ram_enable: bool,
     lower_selection: u8,
     upper_selection: u8,
-    alt_bank_select: bool,
-    upper_is_rom: bool,
+    advanced_mode: bool, // the upper bits also switch 0x0000-0x3fff and RAM
+    multicart: bool,     // MBC1M: 4 bits lower bank, the upper bits select the game
 }
 
 impl MBC1 {
-    pub fn new(rom: &mut Rom, battery: bool) -> Result<Self> {
-        let bank_nb = rom.get_rom_size()? / 16;
-        let ram_size = rom.get_ram_size()?;
-        if ram_size != 0x0 && ram_size != 0x2000 && ram_size != 0x8000 {
-            bail!("Invalid ram size for MBC1: {:x}", ram_size);
-        }
-        let ram_bank_nb = ram_size / 0x2000;
-        let rom_data = rom.read_range(0, rom.get_data_len())?;
-        let mut save_path = rom.path.clone();
-        save_path.set_extension("save");
-        let mut ram_file = if battery {
-            Some(
-                OpenOptions::new()
-                    .write(true)
-                    .read(true)
-                    .create(true)
-                    .open(&save_path)?,
-            )
+    pub fn new(rom: &mut Rom, battery: bool, multicart: bool) -> Result<Self> {
+        let mut ram = vec![[0u8; 0x2000]; ram_banks(rom, "MBC1", MBC_RAM_BANKS)];
+        let save = if battery {
+            Some(open_battery(rom, &mut ram)?.0)
         } else {
             None
         };
-        let mut ram = vec![[0u8; 0x2000]; ram_bank_nb];
-        if let Some(ram_file) = &mut ram_file {
-            let file_len = ram_file.seek(SeekFrom::End(0))?;
-            ram_file.rewind()?;
-            if file_len != 0 {
-                for ram_bank in ram.iter_mut() {
-                    ram_file.read_exact(ram_bank).with_context(|| {
-                        format!("Save file {} is corrupted", save_path.display())
-                    })?;
-                }
-                println!("Save file loaded from {}", save_path.display());
-                ram_file.rewind()?;
-            } else {
-                println!("Save file created at {}", save_path.display());
-            }
-        }
 
-        let mut res = MBC1 {
-            banks: vec![[0; 0x4000]; bank_nb],
+        Ok(MBC1 {
+            banks: rom_banks(rom)?,
             ram,
-            ram_file,
+            save,
             ram_enable: false,
             lower_selection: 1,
             upper_selection: 0,
-            alt_bank_select: true,
-            upper_is_rom: bank_nb > 31,
-        };
-        let mut i = 0;
-        for chunk in rom_data.chunks(0x4000) {
-            res.banks[i][..chunk.len()].copy_from_slice(chunk);
-            i += 1;
-            if i & 0x1f == 0 {
-                i += 1;
-            }
+            advanced_mode: false,
+            multicart,
+        })
+    }
+
+    // Position of the upper bits in the ROM bank number
+    fn upper_shift(&self) -> u8 {
+        if self.multicart {
+            4
+        } else {
+            5
         }
-        Ok(res)
     }
 
-    fn get_rom_bank(&self) -> usize {
-        let upper = if !self.alt_bank_select && self.upper_is_rom {
-            self.upper_selection
+    fn get_zero_bank(&self) -> usize {
+        if self.advanced_mode {
+            ((self.upper_selection << self.upper_shift()) as usize) % self.banks.len()
         } else {
             0
+        }
+    }
+
+    // The zero check is done on the 5 bits, before the multicart drops one:
+    // bank 0x20 reads 0x21, but MBC1M can map bank 0x10.
+    fn get_rom_bank(&self) -> usize {
+        let lower = if self.multicart {
+            self.lower_selection & 0x0f
+        } else {
+            self.lower_selection
         };
-        (self.lower_selection | upper << 5) as usize
+        ((lower | self.upper_selection << self.upper_shift()) as usize) % self.banks.len()
     }
 
     fn get_ram_bank(&self) -> usize {
-        if self.alt_bank_select || self.upper_is_rom {
-            0
+        if self.advanced_mode {
+            self.upper_selection as usize % self.ram.len()
         } else {
-            self.upper_selection as usize
+            0
         }
     }
 }
 
 impl Drop for MBC1 {
     fn drop(&mut self) {
-        if let Err(e) = (|| -> Result<()> {
-            if let Some(ram_file) = &mut self.ram_file {
-                ram_file.rewind()?;
-                for ram_bank in self.ram.iter_mut() {
-                    ram_file.write_all(ram_bank)?;
-                }
-                println!("Game saved!");
-            }
-            Ok(())
-        })() {
-            eprintln!("Game save failed: {e:#}");
-        }
+        report_save(self.save());
     }
 }
 
 impl Cartridge for MBC1 {
     fn read(&self, addr: u16) -> u8 {
         match addr {
-            x if x < 0x4000 => {
-                if !self.alt_bank_select {
-                    self.banks[0][addr as usize]
-                } else {
-                    self.banks[((self.upper_selection << 5) as usize & (self.banks.len() - 1))]
-                        [addr as usize]
-                }
-            }
+            x if x < 0x4000 => self.banks[self.get_zero_bank()][addr as usize],
             x if x < 0x8000 => self.banks[self.get_rom_bank()][addr as usize - 0x4000],
             x if (0xa000..0xc000).contains(&x) => {
                 if self.ram_enable && !self.ram.is_empty(){
@@ -243,102 +371,80 @@ impl Cartridge for MBC1 {
                     let ram_bank = self.get_ram_bank();
                     if index < self.ram[ram_bank].len() {
                         self.ram[ram_bank][index] = val;
+                        mark_dirty(&mut self.save);
                     }
                 }
             }
             x if x < 0x2000 => {
                 self.ram_enable = val & 0xf == 0xa;
+                if !self.ram_enable {
+                    report_save(self.save());
+                }
             }
             x if x < 0x4000 => {
-                let value = if val & 0x1f == 0 { 1 } else { val & 0x1f }; // "bug" of MBC1
-                self.lower_selection = value & (self.banks.len() - 1) as u8;
+                self.lower_selection = if val & 0x1f == 0 { 1 } else { val & 0x1f }; // "bug" of MBC1
             }
             x if x < 0x6000 => {
                 self.upper_selection = val & 0x3;
             }
             x if x < 0x8000 => {
-                self.alt_bank_select = val & 1 == 0;
+                self.advanced_mode = val & 1 != 0;
             }
             _ => {
                 panic!("Illegal cartridge write at {addr:#x}")
             }
         };
     }
+
+    fn save(&mut self) -> Result<()> {
+        match &mut self.save {
+            Some(save) => save.flush(self.ram.as_flattened()),
+            None => Ok(()),
+        }
+    }
 }
 
 struct MBC2 {
     banks: Vec<[u8; 0x4000]>,
     ram: [u8; 0x200],
-    ram_file: Option<File>,
+    save: Option<BatterySave>,
     ram_enable: bool,
     bank_selection: u8,
 }
 
 impl MBC2 {
     pub fn new(rom: &mut Rom, battery: bool) -> Result<Self> {
-        let bank_nb = rom.get_rom_size()? / 16;
-        if bank_nb > 16 {
-            bail!("Invalid bank count fo mbc1: {bank_nb}");
-        }
-        let ram_size = rom.get_ram_size()?;
-        if ram_size != 0 {
-            bail!("Invalid ram size for MBC2: {:x}", ram_size);
-        }
-        let rom_data = rom.read_range(0, rom.get_data_len())?;
-        let mut save_path = rom.path.clone();
-        save_path.set_extension("save");
-        let mut ram_file = if battery {
-            Some(
-                OpenOptions::new()
-                    .write(true)
-                    .read(true)
-                    .create(true)
-                    .open(&save_path)?,
-            )
+        if rom.get_rom_size() > 0x100 {
+            eprintln!("Warning: MBC2 addresses 256KiB of ROM, the rest is unreachable");
+        }
+        if rom.get_ram_size() != 0 {
+            eprintln!("Warning: the header declares RAM, MBC2 has its own 512 half-bytes");
+        }
+        let mut ram = [0u8; 0x200];
+        let save = if battery {
+            let (save, data) = BatterySave::open(rom.save_path(), &rom.legacy_save_path())?;
+            if let Some(data) = data {
+                load_ram(slice::from_mut(&mut ram), &data);
+                pack_mbc2_ram(&mut ram);
+            }
+            Some(save)
         } else {
             None
         };
-        let mut ram = [0u8; 0x200];
-        if let Some(ram_file) = &mut ram_file {
-            let file_len = ram_file.seek(SeekFrom::End(0))?;
-            ram_file.rewind()?;
-            if file_len != 0 {
-                ram_file.read_exact(&mut ram).with_context(|| {
-                    format!("Save file {} is corrupted", save_path.display())
-                })?;
-                println!("Save file loaded from {}", save_path.display());
-                ram_file.rewind()?;
-            } else {
-                println!("Save file created at {}", save_path.display());
-            }
-        }
-
-        let mut res = MBC2 {
-            banks: vec![[0; 0x4000]; bank_nb],
+
+        Ok(MBC2 {
+            banks: rom_banks(rom)?,
             ram,
-            ram_file,
+            save,
             ram_enable: false,
             bank_selection: 1,
-        };
-        for (i, chunk) in rom_data.chunks(0x4000).enumerate() {
-            res.banks[i][..chunk.len()].copy_from_slice(chunk);
-        }
-        Ok(res)
+        })
     }
 }
 
 impl Drop for MBC2 {
     fn drop(&mut self) {
-        if let Err(e) = (|| -> Result<()> {
-            if let Some(ram_file) = &mut self.ram_file {
-                ram_file.rewind()?;
-                ram_file.write_all(&self.ram)?;
-                println!("Game saved!");
-            }
-            Ok(())
-        })() {
-            eprintln!("Game save failed: {e:#}");
-        }
+        report_save(self.save());
     }
 }
 
@@ -349,9 +455,9 @@ impl Cartridge for MBC2 {
                 self.banks[0][addr as usize]
             }
             x if x < 0x8000 => self.banks[self.bank_selection as usize][addr as usize - 0x4000],
-            x if (0xa000..0xa200).contains(&x) => {
+            x if (0xa000..0xc000).contains(&x) => {
                 if self.ram_enable {
-                    self.ram[(addr - 0xa000) as usize]
+                    self.ram[addr as usize & 0x1ff]
                 } else {
                     0
                 }
@@ -361,20 +467,21 @@ impl Cartridge for MBC2 {
     }
     fn write(&mut self, addr: u16, val: u8) {
         match addr {
-            x if (0xa000..0xa200).contains(&x) => {
+            x if (0xa000..0xc000).contains(&x) => {
                 if self.ram_enable {
-                    let index = addr as usize - 0xa000;
-                    if index < self.ram.len() {
-                        self.ram[index] = val;
-                    }
+                    self.ram[addr as usize & 0x1ff] = val | 0xf0;
+                    mark_dirty(&mut self.save);
                 }
             }
             x if x < 0x4000 => {
                 if x & 0x100 != 0 {
                     let masked = val & 0xf;
-                    self.bank_selection = if masked > 0 {masked} else {1};
+                    self.bank_selection = ((if masked > 0 {masked} else {1}) as usize % self.banks.len()) as u8;
                 } else {
                     self.ram_enable = val & 0x0f == 0x0a;
+                    if !self.ram_enable {
+                        report_save(self.save());
+                    }
                 }
             }
             _ => {
@@ -382,125 +489,68 @@ impl Cartridge for MBC2 {
             }
         };
     }
+
+    fn save(&mut self) -> Result<()> {
+        match &mut self.save {
+            Some(save) => save.flush(&self.ram),
+            None => Ok(()),
+        }
+    }
 }
 
 struct MBC3 {
     banks: Vec<[u8; 0x4000]>,
     ram: Vec<[u8; 0x2000]>,
-    save_file: Option<File>,
+    save: Option<BatterySave>,
     ram_enable: bool,
     rom_selection: u8,
     ram_is_rtc: bool,
     ram_rtc_selection: u8,
-    time_offset: Duration,
-    latched_time: Option<OffsetDateTime>,
-    time_stopped: Option<OffsetDateTime>,
+    rtc: Option<Rtc>,
 }
 
 impl MBC3 {
-    pub fn new(rom: &mut Rom, battery: bool) -> Result<Self> {
-        let bank_nb = rom.get_rom_size()? / 16;
-        let ram_size = rom.get_ram_size()?;
-        if ram_size != 0x0 && ram_size != 0x2000 && ram_size != 0x4000 && ram_size != 0x8000 {
-            bail!("Invalid ram size for MBC3: {:x}", ram_size);
-        }
-        let ram_bank_nb = ram_size / 0x2000;
-        let rom_data = rom.read_range(0, rom.get_data_len())?;
-        let mut save_path = rom.path.clone();
-        save_path.set_extension("save");
-        let mut save_file = if battery {
-            Some(
-                OpenOptions::new()
-                    .write(true)
-                    .read(true)
-                    .create(true)
-                    .open(&save_path)?,
-            )
+    pub fn new(rom: &mut Rom, battery: bool, clock: Option<ClockSource>) -> Result<Self> {
+        let mut ram = vec![[0u8; 0x2000]; ram_banks(rom, "MBC3", MBC_RAM_BANKS)];
+        let mut rtc = clock.map(Rtc::new);
+        let save = if battery {
+            let save_path = rom.save_path();
+            let (save, data) = BatterySave::open(save_path.clone(), &rom.legacy_save_path())?;
+            if let Some(data) = data {
+                let footer = &data[load_ram(&mut ram, &data)..];
+                if let (Some(clock), false) = (clock, footer.is_empty()) {
+                    let now = OffsetDateTime::now_utc().unix_timestamp();
+                    match Rtc::from_footer(footer, clock, now) {
+                        Some(loaded) => rtc = Some(loaded),
+                        None => eprintln!("Unknown RTC data in {}, clock reset", save_path.display()),
+                    }
+                }
+            }
+            Some(save)
         } else {
             None
         };
-        let mut ram = vec![[0u8; 0x2000]; ram_bank_nb];
-        let mut time_offset = OffsetDateTime::UNIX_EPOCH - OffsetDateTime::now_utc();
-        let mut time_stopped = Some(OffsetDateTime::UNIX_EPOCH);
-        if let Some(save_file) = &mut save_file {
-            let file_len = save_file.seek(SeekFrom::End(0))?;
-            save_file.rewind()?;
-            if file_len != 0 {
-                for ram_bank in ram.iter_mut() {
-                    save_file.read_exact(ram_bank).with_context(|| {
-                        format!("Save file {} is corrupted", save_path.display())
-                    })?;
-                }
-                let mut time_bytes = [0u8; 8];
-                save_file.read_exact(&mut time_bytes).context("Failed to read save time info")?;
-                time_offset = Duration::new(i64::from_be_bytes(time_bytes), 0);
-                let mut time_stopped_byte = 0u8;
-                save_file.read_exact(slice::from_mut(&mut time_stopped_byte))?;
-                if time_stopped_byte != 0 {
-                    time_stopped = Some(OffsetDateTime::UNIX_EPOCH + time_offset);
-                } else {
-                    time_stopped = None
-                }
-                println!("Save file loaded from {}", save_path.display());
-                save_file.rewind()?;
-            } else {
-                println!("Save file created at {}", save_path.display());
-            }
-        }
 
-        let mut res = MBC3 {
-            banks: vec![[0; 0x4000]; bank_nb],
+        Ok(MBC3 {
+            banks: rom_banks(rom)?,
             ram,
-            save_file,
+            save,
             ram_enable: false,
             rom_selection: 1,
             ram_is_rtc: false,
             ram_rtc_selection: 0,
-            time_offset,
-            latched_time: None,
-            time_stopped
-        };
-        for (i, chunk) in rom_data.chunks(0x4000).enumerate() {
-            res.banks[i][..chunk.len()].copy_from_slice(chunk);
-        }
-        Ok(res)
-    }
-
-    fn get_game_time(&self) -> OffsetDateTime {
-        self.latched_time.or(self.time_stopped).unwrap_or_else(||OffsetDateTime::now_utc() + self.time_offset)
-    }
-
-    fn set_game_time(&mut self, new_time: &OffsetDateTime) {
-        if self.time_stopped.is_some() {
-            self.time_stopped = Some(*new_time);
-        } else {
-            eprintln!("Setting the clock while it is running");
-            self.time_offset = *new_time - OffsetDateTime::now_utc();
-        }
+            rtc,
+        })
     }
 }
 
 impl Drop for MBC3 {
     fn drop(&mut self) {
-        if let Err(e) = (|| -> Result<()> {
-            if let Some(ram_file) = &mut self.save_file {
-                ram_file.rewind()?;
-                for ram_bank in self.ram.iter_mut() {
-                    ram_file.write_all(ram_bank)?;
-                }
-                if let Some(time_stopped) = self.time_stopped {
-                    ram_file.write_all(&(time_stopped - OffsetDateTime::UNIX_EPOCH).whole_seconds().to_be_bytes())?;
-                    ram_file.write_all(&[1u8])?;
-                } else {
-                    ram_file.write_all(&self.time_offset.whole_seconds().to_le_bytes())?;
-                    ram_file.write_all(&[0u8])?;
-                }
-                println!("Game saved!");
-            }
-            Ok(())
-        })() {
-            eprintln!("Game save failed: {e:#}");
+        // Keep the clock progress even if the RAM did not change
+        if let (Some(save), Some(_)) = (&mut self.save, &self.rtc) {
+            save.mark_dirty();
         }
+        report_save(self.save());
     }
 }
 
@@ -516,27 +566,14 @@ impl Cartridge for MBC3 {
                     return 0;
                 }
                 if self.ram_is_rtc {
-                    match self.ram_rtc_selection {
-                        8 => self.get_game_time().second(),
-                        9 => self.get_game_time().minute(),
-                        10 => self.get_game_time().hour(),
-                        11 => ((self.get_game_time() - OffsetDateTime::UNIX_EPOCH).whole_days() & 0xff) as u8,
-                        12 => {
-                            let day_count = (self.get_game_time() - OffsetDateTime::UNIX_EPOCH).whole_days();
-                            let mut res = 0u8;
-                            if day_count & 0x100 != 0 {
-                                res |= 1;
-                            }
-                            if day_count>= 0x200 {
-                                res |= 0x80;
-                            }
-                            res |= if self.time_stopped.is_some() {0x40} else {0};
-                            res
-                        },
-                        _ => panic!("Invalid MBC3 RTC selection at {:x}", self.ram_rtc_selection)
+                    match &self.rtc {
+                        Some(rtc) => rtc.read(self.ram_rtc_selection),
+                        None => 0xff,
                     }
                 } else {
-                    self.ram[self.ram_rtc_selection as usize][(addr - 0xa000) as usize]
+                    self.ram
+                        .get(self.ram_rtc_selection as usize)
+                        .map_or(0xff, |bank| bank[(addr - 0xa000) as usize])
                 }
             }
             _ => panic!("Illegal cartridge read at {addr:#x}"),
@@ -547,49 +584,24 @@ impl Cartridge for MBC3 {
             x if (0xa000..0xc000).contains(&x) => {
                 if self.ram_enable {
                     if self.ram_is_rtc {
-                        match self.ram_rtc_selection {
-                            8 => {
-                                self.set_game_time(&self.get_game_time().replace_second(val).expect("Invalid second write to RTC"));
-                            },
-                            9 => self.set_game_time(&self.get_game_time().replace_minute(val).expect("Invalid minute write to RTC")),
-                            10 => self.set_game_time(&self.get_game_time().replace_hour(val).expect("Invalid hour write to RTC")),
-                            11 => {
-                                let old_offset = self.get_game_time() - OffsetDateTime::UNIX_EPOCH;
-                                let old_seconds = old_offset.whole_seconds() % (3600*24);
-                                let old_days = old_offset.whole_days();
-                                let new_offset = Duration::seconds(old_seconds) + Duration::days(val as i64 + (old_days & 0x100));
-                                self.set_game_time(&(OffsetDateTime::UNIX_EPOCH + new_offset));
-                            }
-                            12 => {
-                                let old_offset = self.get_game_time() - OffsetDateTime::UNIX_EPOCH;
-                                let old_seconds = old_offset.whole_seconds() % (3600*24);
-                                let old_days = old_offset.whole_days();
-                                let new_offset = Duration::seconds(old_seconds) + Duration::days((((val & 1) as i64) << 8) | (old_days & 0xff));
-                                self.set_game_time(&(OffsetDateTime::UNIX_EPOCH + new_offset));
-                                // TODO: ignore overflow flag at 0x80 for now
-                                if val & 0x40 != 0 {
-                                    if self.time_stopped.is_none() {
-                                        self.time_stopped = Some(self.get_game_time());
-                                    }
-                                } else if let Some(time_stopped) = self.time_stopped{
-                                    self.time_offset = time_stopped - OffsetDateTime::now_utc();
-                                    self.time_stopped = None;
-                                }
-                            },
-                            _ => panic!("Invalid MBC3 RTC selection at {:x}", self.ram_rtc_selection)
-                        };
-                    } else {
-                        let index = addr as usize - 0xa000;
-                        self.ram[self.ram_rtc_selection as usize][index] = val;
+                        if let Some(rtc) = &mut self.rtc {
+                            rtc.write(self.ram_rtc_selection, val);
+                        }
+                    } else if let Some(bank) = self.ram.get_mut(self.ram_rtc_selection as usize) {
+                        bank[addr as usize - 0xa000] = val;
+                        mark_dirty(&mut self.save);
                     }
                 }
             }
             x if x < 0x2000 => {
                 self.ram_enable = val & 0xf == 0xa;
+                if !self.ram_enable {
+                    report_save(self.save());
+                }
             }
             x if x < 0x4000 => {
                 let value = val & 0x7f;
-                self.rom_selection = if value > 0 {value} else {1};
+                self.rom_selection = ((if value > 0 {value} else {1}) as usize % self.banks.len()) as u8;
             }
             x if x < 0x6000 => {
                 match val {
@@ -608,10 +620,8 @@ impl Cartridge for MBC3 {
                 }
             }
             x if x < 0x8000 => {
-                if val == 0 {
-                    self.latched_time = None;
-                } else {
-                    self.latched_time = Some(self.get_game_time());
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(val);
                 }
             }
             _ => {
@@ -619,109 +629,1353 @@ impl Cartridge for MBC3 {
             }
         };
     }
-}
 
+    fn tick(&mut self) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick();
+        }
+    }
 
-#[derive(TryFromPrimitive, Debug)]
-#[repr(u8)]
-enum MbcType {
-    NRom = 0x0,
-    Mbc1 = 0x1,
-    Mbc1Ram = 0x2,
-    Mbc1RamBattery = 0x3,
-    Mbc2 = 0x5,
-    Mbc2Battery = 0x6,
-    RomRam = 0x8,
-    RomRamBattery = 0x9,
-    MMm01 = 0xb,
-    Mmm01Ram = 0xc,
-    Mmm1RamBattery = 0xd,
-    Mbc3TimerBattery = 0xF,
-    Mbc3TimerRamBattery = 0x10,
-    Mbc3 = 0x11,
-    Mbc3Ram = 0x12,
-    Mbc3RamBattery = 0x13,
-    Mbc5 = 0x19,
-    Mbc5Ram = 0x1a,
-    Mbc5RamBattery = 0x1b,
-    Mbc5Rumble = 0x1c,
-    Mbc5RumbleRam = 0x1d,
-    Mbc5RumbleRamBattery = 0x1e,
-    Mbc6 = 0x20,
-    Mbc6All = 0x21,
-    Unknown,
+    fn rtc(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+
+    fn save(&mut self) -> Result<()> {
+        let Some(save) = &mut self.save else {
+            return Ok(());
+        };
+        let mut data = self.ram.as_flattened().to_vec();
+        if let Some(rtc) = &self.rtc {
+            data.extend_from_slice(&rtc.footer(OffsetDateTime::now_utc().unix_timestamp()));
+        }
+        save.flush(&data)
+    }
 }
 
-struct Rom {
-    data: Vec<u8>,
-    path: PathBuf,
+// Multicart with a menu in the last 32 KiB. The menu sets the base banks and
+// masks of a game then maps it, after which the game sees an MBC1.
+struct MMM01 {
+    banks: Vec<[u8; 0x4000]>,
+    ram: Vec<[u8; 0x2000]>,
+    save: Option<BatterySave>,
+    mapped: bool,
+    ram_enable: bool,
+    rom_low: u8,
+    rom_mid: u8,
+    rom_high: u8,
+    rom_mask: u8, // bits of rom_low the game cannot change once mapped
+    ram_low: u8,
+    ram_high: u8,
+    ram_mask: u8,
+    mode: bool,
+    mode_lock: bool,
 }
 
-impl Rom {
-    fn new(path: &str) -> Result<Self> {
-        let mut file = File::open(path).with_context(|| format!("Error opening {path}"))?;
-        let mut buf: Vec<u8> = Vec::new();
-        file.read_to_end(&mut buf)?;
-        Ok(Rom {
-            data: buf,
-            path: PathBuf::try_from(path)?,
+impl MMM01 {
+    pub fn new(rom: &mut Rom, battery: bool) -> Result<Self> {
+        let banks = rom_banks(rom)?;
+        let mut ram = vec![[0u8; 0x2000]; header_ram_banks(rom)];
+        let save = if battery {
+            Some(open_battery(rom, &mut ram)?.0)
+        } else {
+            None
+        };
+        Ok(MMM01 {
+            banks,
+            ram,
+            save,
+            mapped: false,
+            ram_enable: false,
+            rom_low: 0,
+            rom_mid: 0,
+            rom_high: 0,
+            rom_mask: 0,
+            ram_low: 0,
+            ram_high: 0,
+            ram_mask: 0,
+            mode: false,
+            mode_lock: false,
         })
     }
 
-    fn get_title(&mut self) -> Result<String> {
-        let str_arr = self.read_range(0x134, 0x143)?;
-        let mut s = str::from_utf8(str_arr).context("Invalid UTF-8 in game title")?;
-        s = s.trim_matches(char::from(0));
-        Ok(s.to_owned())
+    fn get_rom_bank(&self, zero: bool) -> usize {
+        let len = self.banks.len();
+        if !self.mapped {
+            return if zero { len - 2 } else { len - 1 };
+        }
+        let low = if zero {
+            self.rom_low & self.rom_mask
+        } else if self.rom_low & !self.rom_mask & 0x1f == 0 {
+            self.rom_low | 1
+        } else {
+            self.rom_low
+        };
+        ((self.rom_high as usize) << 7 | (self.rom_mid as usize) << 5 | low as usize) % len
     }
 
-    fn is_gbc(&self) -> bool {
-        self.data[0x143] & 0x80 != 0
+    fn get_ram_bank(&self) -> usize {
+        let low = if self.mode { self.ram_low } else { self.ram_low & self.ram_mask };
+        (self.ram_high << 2 | low) as usize % self.ram.len()
     }
+}
 
-    fn get_ram_size(&self) -> Result<usize> {
-        Ok(match self.data[0x149] {
-            0 => 0,
-            1 => 0x800,
-            2 => 0x2000,
-            3 => 0x8000,
-            4 => 0x20000,
-            5 => 0x10000,
-            x => bail!("Invalid RAM size: {}", x),
-        })
+impl Drop for MMM01 {
+    fn drop(&mut self) {
+        report_save(self.save());
+    }
+}
+
+impl Cartridge for MMM01 {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            x if x < 0x4000 => self.banks[self.get_rom_bank(true)][addr as usize],
+            x if x < 0x8000 => self.banks[self.get_rom_bank(false)][addr as usize - 0x4000],
+            x if (0xa000..0xc000).contains(&x) => {
+                if self.ram_enable && !self.ram.is_empty() {
+                    self.ram[self.get_ram_bank()][addr as usize - 0xa000]
+                } else {
+                    0xff
+                }
+            }
+            _ => panic!("Illegal cartridge read at {addr:#x}"),
+        }
+    }
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            x if (0xa000..0xc000).contains(&x) => {
+                if self.ram_enable && !self.ram.is_empty() {
+                    let bank = self.get_ram_bank();
+                    self.ram[bank][addr as usize - 0xa000] = val;
+                    mark_dirty(&mut self.save);
+                }
+            }
+            x if x < 0x2000 => {
+                self.ram_enable = val & 0xf == 0xa;
+                if !self.mapped {
+                    self.ram_mask = (val >> 4) & 0x3;
+                    self.mapped = val & 0x40 != 0;
+                }
+                if !self.ram_enable {
+                    report_save(self.save());
+                }
+            }
+            x if x < 0x4000 => {
+                if self.mapped {
+                    self.rom_low = self.rom_low & self.rom_mask | val & !self.rom_mask & 0x1f;
+                } else {
+                    self.rom_low = val & 0x1f;
+                    self.rom_mid = (val >> 5) & 0x3;
+                }
+            }
+            x if x < 0x6000 => {
+                if self.mapped {
+                    self.ram_low = self.ram_low & self.ram_mask | val & !self.ram_mask & 0x3;
+                } else {
+                    self.ram_low = val & 0x3;
+                    self.ram_high = (val >> 2) & 0x3;
+                    self.rom_high = (val >> 4) & 0x3;
+                    self.mode_lock = val & 0x40 != 0;
+                }
+            }
+            x if x < 0x8000 => {
+                if !(self.mapped && self.mode_lock) {
+                    self.mode = val & 1 != 0;
+                }
+                if !self.mapped {
+                    self.rom_mask = (val & 0x3c) >> 1;
+                }
+            }
+            _ => {
+                panic!("Illegal cartridge write at {addr:#x}")
+            }
+        };
+    }
+
+    fn save(&mut self) -> Result<()> {
+        match &mut self.save {
+            Some(save) => save.flush(self.ram.as_flattened()),
+            None => Ok(()),
+        }
     }
+}
+
+// Flash commands, the unlock cycles are not checked against their addresses
+#[derive(Clone, Copy, PartialEq)]
+enum FlashState {
+    Read,
+    Unlock1,
+    Unlock2,
+    Erase,
+    EraseUnlock1,
+    EraseUnlock2,
+    Program,
+}
+
+// 8 KiB ROM banks and 4 KiB RAM banks in two halves each, plus 1 MiB of flash
+// that can be mapped in place of the ROM
+struct MBC6 {
+    banks: Vec<[u8; 0x2000]>,
+    flash: Vec<[u8; 0x2000]>,
+    ram: Vec<[u8; 0x1000]>,
+    save: Option<BatterySave>,
+    ram_enable: bool,
+    flash_enable: bool,
+    flash_write_enable: bool,
+    rom_selection: [u8; 2],
+    flash_selected: [bool; 2],
+    ram_selection: [u8; 2],
+    flash_state: FlashState,
+}
 
-    fn get_rom_size(&self) -> Result<usize> {
-        Ok(match self.data[0x148] {
-            0 => 0x20,
-            1 => 0x40,
-            2 => 0x80,
-            3 => 0x100,
-            4 => 0x200,
-            5 => 0x400,
-            6 => 0x800,
-            7 => 0x1000,
-            8 => 0x2000,
-            0x52 => 0x480,
-            0x53 => 0x500,
-            0x54 => 0x600,
-            x => bail!("Invalid ROM size: {}", x),
+impl MBC6 {
+    pub fn new(rom: &mut Rom, battery: bool) -> Result<Self> {
+        let banks = rom_banks(rom)?;
+        let mut ram = vec![[0u8; 0x1000]; 8];
+        let mut flash = vec![[0xffu8; 0x2000]; 0x80];
+        let save = if battery {
+            let (save, extra) = open_battery(rom, &mut ram)?;
+            if !extra.is_empty() {
+                load_ram(&mut flash, &extra);
+            }
+            Some(save)
+        } else {
+            None
+        };
+        Ok(MBC6 {
+            banks,
+            flash,
+            ram,
+            save,
+            ram_enable: false,
+            flash_enable: false,
+            flash_write_enable: false,
+            rom_selection: [2, 3],
+            flash_selected: [false; 2],
+            ram_selection: [0; 2],
+            flash_state: FlashState::Read,
         })
     }
 
-    fn get_mbc_type(&mut self) -> Result<MbcType> {
-        let mbc_code = self.data[0x147];
-        MbcType::try_from(mbc_code).context(format!("Unsuported MBC: {mbc_code:x}"))
+    fn flash_write(&mut self, bank: usize, addr: usize, val: u8) {
+        use FlashState::*;
+        self.flash_state = match (self.flash_state, val) {
+            (_, 0xf0) => Read,
+            (Read, 0xaa) => Unlock1,
+            (Unlock1, 0x55) => Unlock2,
+            (Unlock2, 0x80) => Erase,
+            (Unlock2, 0xa0) => Program,
+            (Erase, 0xaa) => EraseUnlock1,
+            (EraseUnlock1, 0x55) => EraseUnlock2,
+            (EraseUnlock2, 0x30) => {
+                // 128 KiB sectors
+                let sector = bank & !0xf;
+                self.flash[sector..sector + 0x10].fill([0xff; 0x2000]);
+                mark_dirty(&mut self.save);
+                Read
+            }
+            (EraseUnlock2, 0x10) => {
+                self.flash.fill([0xff; 0x2000]);
+                mark_dirty(&mut self.save);
+                Read
+            }
+            (Program, _) => {
+                // Programming can only clear bits
+                self.flash[bank][addr] &= val;
+                mark_dirty(&mut self.save);
+                Read
+            }
+            _ => Read,
+        };
     }
+}
 
-    fn get_data_len(&self) -> usize {
-        self.data.len()
+impl Drop for MBC6 {
+    fn drop(&mut self) {
+        report_save(self.save());
     }
+}
 
-    fn read_range(&self, begin: usize, end: usize) -> Result<&[u8]> {
-        if self.data.len() < end {
-            bail!("Load ROM out-of-bound read. Maybe the file is corrupted ?")
-        }
-        Ok(&self.data[begin..end])
+impl Cartridge for MBC6 {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            x if x < 0x4000 => self.banks[addr as usize >> 13][addr as usize & 0x1fff],
+            x if x < 0x8000 => {
+                let half = (addr as usize - 0x4000) >> 13;
+                let bank = self.rom_selection[half] as usize;
+                if self.flash_selected[half] && self.flash_enable {
+                    self.flash[bank & 0x7f][addr as usize & 0x1fff]
+                } else {
+                    self.banks[bank % self.banks.len()][addr as usize & 0x1fff]
+                }
+            }
+            x if (0xa000..0xc000).contains(&x) => {
+                if self.ram_enable {
+                    let half = (addr as usize - 0xa000) >> 12;
+                    self.ram[self.ram_selection[half] as usize & 0x7][addr as usize & 0xfff]
+                } else {
+                    0xff
+                }
+            }
+            _ => panic!("Illegal cartridge read at {addr:#x}"),
+        }
+    }
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            x if (0xa000..0xc000).contains(&x) => {
+                if self.ram_enable {
+                    let half = (addr as usize - 0xa000) >> 12;
+                    self.ram[self.ram_selection[half] as usize & 0x7][addr as usize & 0xfff] = val;
+                    mark_dirty(&mut self.save);
+                }
+            }
+            x if x < 0x0400 => {
+                self.ram_enable = val & 0xf == 0xa;
+                if !self.ram_enable {
+                    report_save(self.save());
+                }
+            }
+            x if x < 0x0800 => self.ram_selection[0] = val,
+            x if x < 0x0c00 => self.ram_selection[1] = val,
+            x if x < 0x1000 => self.flash_enable = val & 1 != 0,
+            x if x < 0x2000 => {
+                if x == 0x1000 {
+                    self.flash_write_enable = val & 1 != 0;
+                }
+            }
+            x if x < 0x4000 => {
+                let half = ((x - 0x2000) >> 12) as usize;
+                if x & 0x800 == 0 {
+                    self.rom_selection[half] = val;
+                } else {
+                    self.flash_selected[half] = val & 0x08 != 0;
+                }
+            }
+            x if x < 0x8000 => {
+                let half = (addr as usize - 0x4000) >> 13;
+                if self.flash_selected[half] && self.flash_enable && self.flash_write_enable {
+                    let bank = self.rom_selection[half] as usize & 0x7f;
+                    self.flash_write(bank, addr as usize & 0x1fff, val);
+                }
+            }
+            _ => {
+                panic!("Illegal cartridge write at {addr:#x}")
+            }
+        };
+    }
+
+    fn save(&mut self) -> Result<()> {
+        let Some(save) = &mut self.save else {
+            return Ok(());
+        };
+        let mut data = self.ram.as_flattened().to_vec();
+        data.extend_from_slice(self.flash.as_flattened());
+        save.flush(&data)
+    }
+}
+
+// MBC5 like banking with a 2 axis accelerometer and a serial EEPROM in place
+// of the RAM, both behind two enable registers
+struct MBC7 {
+    banks: Vec<[u8; 0x4000]>,
+    eeprom: Eeprom,
+    save: Option<BatterySave>,
+    ram_enable: [bool; 2],
+    rom_selection: u8,
+    tilt: (f32, f32),
+    latched: (u16, u16),
+    erased: bool,
+}
+
+impl MBC7 {
+    pub fn new(rom: &mut Rom) -> Result<Self> {
+        let banks = rom_banks(rom)?;
+        let mut eeprom = Eeprom::new();
+        let (save, _) = open_battery(rom, slice::from_mut(&mut eeprom.data))?;
+        Ok(MBC7 {
+            banks,
+            eeprom,
+            save: Some(save),
+            ram_enable: [false; 2],
+            rom_selection: 1,
+            tilt: (0.0, 0.0),
+            latched: (0x8000, 0x8000),
+            erased: false,
+        })
+    }
+}
+
+impl Drop for MBC7 {
+    fn drop(&mut self) {
+        report_save(self.save());
+    }
+}
+
+impl Cartridge for MBC7 {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            x if x < 0x4000 => self.banks[0][addr as usize],
+            x if x < 0x8000 => self.banks[self.rom_selection as usize][addr as usize - 0x4000],
+            x if (0xa000..0xb000).contains(&x) && self.ram_enable == [true; 2] => {
+                let (x, y) = self.latched;
+                match (addr >> 4) & 0xf {
+                    2 => x as u8,
+                    3 => (x >> 8) as u8,
+                    4 => y as u8,
+                    5 => (y >> 8) as u8,
+                    6 => 0,
+                    8 => self.eeprom.read(),
+                    _ => 0xff,
+                }
+            }
+            x if (0xa000..0xc000).contains(&x) => 0xff,
+            _ => panic!("Illegal cartridge read at {addr:#x}"),
+        }
+    }
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            x if (0xa000..0xb000).contains(&x) => {
+                if self.ram_enable != [true; 2] {
+                    return;
+                }
+                match (addr >> 4) & 0xf {
+                    0 if val == 0x55 => {
+                        self.erased = true;
+                        self.latched = (0x8000, 0x8000);
+                    }
+                    1 if val == 0xaa && self.erased => {
+                        // About 0x70 per g around 0x81d0
+                        let axis = |t: f32| (0x81d0 as f32 + t.clamp(-1.0, 1.0) * 0x70 as f32) as u16;
+                        self.latched = (axis(-self.tilt.0), axis(self.tilt.1));
+                        self.erased = false;
+                    }
+                    8 => {
+                        let before = self.eeprom.data;
+                        self.eeprom.write(val);
+                        if before != self.eeprom.data {
+                            mark_dirty(&mut self.save);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            x if (0xb000..0xc000).contains(&x) => {}
+            x if x < 0x2000 => {
+                self.ram_enable[0] = val & 0xf == 0xa;
+                if !self.ram_enable[0] {
+                    report_save(self.save());
+                }
+            }
+            x if x < 0x4000 => {
+                self.rom_selection = ((val & 0x7f) as usize % self.banks.len()) as u8;
+            }
+            x if x < 0x6000 => self.ram_enable[1] = val == 0x40,
+            x if x < 0x8000 => {}
+            _ => {
+                panic!("Illegal cartridge write at {addr:#x}")
+            }
+        };
+    }
+
+    fn set_tilt(&mut self, x: f32, y: f32) {
+        self.tilt = (x, y);
+    }
+
+    fn save(&mut self) -> Result<()> {
+        match &mut self.save {
+            Some(save) => save.flush(&self.eeprom.data),
+            None => Ok(()),
+        }
+    }
+}
+
+// Hudson mapper with an infrared port in place of the RAM. Nothing is ever
+// received, and the LED goes nowhere.
+struct HuC1 {
+    banks: Vec<[u8; 0x4000]>,
+    ram: Vec<[u8; 0x2000]>,
+    save: Option<BatterySave>,
+    ir_mode: bool,
+    rom_selection: u8,
+    ram_selection: u8,
+}
+
+impl HuC1 {
+    pub fn new(rom: &mut Rom) -> Result<Self> {
+        let banks = rom_banks(rom)?;
+        let mut ram = vec![[0u8; 0x2000]; header_ram_banks(rom)];
+        let (save, _) = open_battery(rom, &mut ram)?;
+        Ok(HuC1 {
+            banks,
+            ram,
+            save: Some(save),
+            ir_mode: false,
+            rom_selection: 1,
+            ram_selection: 0,
+        })
+    }
+}
+
+impl Drop for HuC1 {
+    fn drop(&mut self) {
+        report_save(self.save());
+    }
+}
+
+impl Cartridge for HuC1 {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            x if x < 0x4000 => self.banks[0][addr as usize],
+            x if x < 0x8000 => self.banks[self.rom_selection as usize][addr as usize - 0x4000],
+            x if (0xa000..0xc000).contains(&x) => {
+                if self.ir_mode {
+                    0xc0 // no light
+                } else if self.ram.is_empty() {
+                    0xff
+                } else {
+                    self.ram[self.ram_selection as usize][addr as usize - 0xa000]
+                }
+            }
+            _ => panic!("Illegal cartridge read at {addr:#x}"),
+        }
+    }
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            x if (0xa000..0xc000).contains(&x) => {
+                if !self.ir_mode && !self.ram.is_empty() {
+                    self.ram[self.ram_selection as usize][addr as usize - 0xa000] = val;
+                    mark_dirty(&mut self.save);
+                }
+            }
+            x if x < 0x2000 => self.ir_mode = val == 0x0e,
+            x if x < 0x4000 => {
+                let value = val & 0x3f;
+                self.rom_selection = ((if value > 0 { value } else { 1 }) as usize % self.banks.len()) as u8;
+            }
+            x if x < 0x6000 => {
+                self.ram_selection = (val & 0x3) % self.ram.len().max(1) as u8;
+            }
+            x if x < 0x8000 => {}
+            _ => {
+                panic!("Illegal cartridge write at {addr:#x}")
+            }
+        };
+    }
+
+    fn save(&mut self) -> Result<()> {
+        match &mut self.save {
+            Some(save) => save.flush(self.ram.as_flattened()),
+            None => Ok(()),
+        }
+    }
+}
+
+// HuC3 clock, talked to with nibble commands. It counts minutes of the day and
+// days, kept as the host time of day 0 minute 0.
+struct HuC3Rtc {
+    memory: [u8; 0x100], // nibbles, the time is copied to and from 0x00-0x05
+    address: u8,
+    response: u8,
+    epoch: i64,
+}
+
+impl HuC3Rtc {
+    fn command(&mut self, val: u8, now: i64) {
+        let arg = val & 0xf;
+        match val >> 4 {
+            0x1 => {
+                self.response = 0x10 | self.memory[self.address as usize];
+                self.address = self.address.wrapping_add(1);
+            }
+            0x3 => {
+                self.memory[self.address as usize] = arg;
+                self.address = self.address.wrapping_add(1);
+            }
+            0x4 => self.address = self.address & 0xf0 | arg,
+            0x5 => self.address = self.address & 0x0f | arg << 4,
+            0x6 => match arg {
+                0 => {
+                    let elapsed = (now - self.epoch).max(0);
+                    let minutes = (elapsed / 60 % 1440) as u16;
+                    let days = (elapsed / 86400) as u16 & 0xfff;
+                    self.store(0, minutes);
+                    self.store(3, days);
+                }
+                1 => {
+                    let minutes = self.load(0) as i64;
+                    let days = self.load(3) as i64;
+                    self.epoch = now - days * 86400 - minutes * 60;
+                }
+                2 => self.response = 0x61, // ready
+                _ => {}                    // tone generator, not emulated
+            },
+            _ => {}
+        }
+    }
+
+    // 12 bits values as 3 nibbles, least significant first
+    fn store(&mut self, index: usize, value: u16) {
+        for i in 0..3 {
+            self.memory[index + i] = (value >> (i * 4)) as u8 & 0xf;
+        }
+    }
+
+    fn load(&self, index: usize) -> u16 {
+        (0..3).map(|i| (self.memory[index + i] as u16) << (i * 4)).sum()
+    }
+}
+
+struct HuC3 {
+    banks: Vec<[u8; 0x4000]>,
+    ram: Vec<[u8; 0x2000]>,
+    save: Option<BatterySave>,
+    mode: u8,
+    rom_selection: u8,
+    ram_selection: u8,
+    rtc: HuC3Rtc,
+}
+
+impl HuC3 {
+    pub fn new(rom: &mut Rom) -> Result<Self> {
+        let banks = rom_banks(rom)?;
+        let mut ram = vec![[0u8; 0x2000]; header_ram_banks(rom)];
+        let (save, footer) = open_battery(rom, &mut ram)?;
+        // The footer is the epoch as 64 bits little endian
+        let epoch = match footer.get(..8) {
+            Some(bytes) => i64::from_le_bytes(bytes.try_into().expect("8 bytes")),
+            None => OffsetDateTime::now_utc().unix_timestamp(),
+        };
+        Ok(HuC3 {
+            banks,
+            ram,
+            save: Some(save),
+            mode: 0,
+            rom_selection: 1,
+            ram_selection: 0,
+            rtc: HuC3Rtc {
+                memory: [0; 0x100],
+                address: 0,
+                response: 0,
+                epoch,
+            },
+        })
+    }
+}
+
+impl Drop for HuC3 {
+    fn drop(&mut self) {
+        report_save(self.save());
+    }
+}
+
+impl Cartridge for HuC3 {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            x if x < 0x4000 => self.banks[0][addr as usize],
+            x if x < 0x8000 => self.banks[self.rom_selection as usize][addr as usize - 0x4000],
+            x if (0xa000..0xc000).contains(&x) => match self.mode {
+                0x0 | 0xa if !self.ram.is_empty() => {
+                    self.ram[self.ram_selection as usize][addr as usize - 0xa000]
+                }
+                0xc => 0x80 | self.rtc.response,
+                0xd => 0x01, // commands complete immediately
+                0xe => 0xc0, // no infrared light
+                _ => 0xff,
+            },
+            _ => panic!("Illegal cartridge read at {addr:#x}"),
+        }
+    }
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            x if (0xa000..0xc000).contains(&x) => match self.mode {
+                0xa if !self.ram.is_empty() => {
+                    self.ram[self.ram_selection as usize][addr as usize - 0xa000] = val;
+                    mark_dirty(&mut self.save);
+                }
+                0xb => {
+                    let epoch = self.rtc.epoch;
+                    self.rtc.command(val, OffsetDateTime::now_utc().unix_timestamp());
+                    if epoch != self.rtc.epoch {
+                        mark_dirty(&mut self.save);
+                    }
+                }
+                _ => {}
+            },
+            x if x < 0x2000 => {
+                self.mode = val & 0xf;
+                if self.mode != 0xa {
+                    report_save(self.save());
+                }
+            }
+            x if x < 0x4000 => {
+                let value = val & 0x7f;
+                self.rom_selection = ((if value > 0 { value } else { 1 }) as usize % self.banks.len()) as u8;
+            }
+            x if x < 0x6000 => {
+                self.ram_selection = (val & 0x3) % self.ram.len().max(1) as u8;
+            }
+            x if x < 0x8000 => {}
+            _ => {
+                panic!("Illegal cartridge write at {addr:#x}")
+            }
+        };
+    }
+
+    fn save(&mut self) -> Result<()> {
+        let Some(save) = &mut self.save else {
+            return Ok(());
+        };
+        let mut data = self.ram.as_flattened().to_vec();
+        data.extend_from_slice(&self.rtc.epoch.to_le_bytes());
+        save.flush(&data)
+    }
+}
+
+// 128 KiB of RAM holding the photos, with the sensor registers mapped in
+// place of the RAM by bank 0x10
+struct PocketCamera {
+    banks: Vec<[u8; 0x4000]>,
+    ram: Vec<[u8; 0x2000]>,
+    save: Option<BatterySave>,
+    ram_enable: bool,
+    rom_selection: u8,
+    ram_selection: u8,
+    camera: Camera,
+}
+
+impl PocketCamera {
+    pub fn new(rom: &mut Rom) -> Result<Self> {
+        let banks = rom_banks(rom)?;
+        let mut ram = vec![[0u8; 0x2000]; 0x10];
+        let (save, _) = open_battery(rom, &mut ram)?;
+        Ok(PocketCamera {
+            banks,
+            ram,
+            save: Some(save),
+            ram_enable: false,
+            rom_selection: 1,
+            ram_selection: 0,
+            camera: Camera::new(),
+        })
+    }
+}
+
+impl Drop for PocketCamera {
+    fn drop(&mut self) {
+        report_save(self.save());
+    }
+}
+
+impl Cartridge for PocketCamera {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            x if x < 0x4000 => self.banks[0][addr as usize],
+            x if x < 0x8000 => self.banks[self.rom_selection as usize][addr as usize - 0x4000],
+            // The RAM can be read even when writes are disabled
+            x if (0xa000..0xc000).contains(&x) => {
+                if self.ram_selection & 0x10 != 0 {
+                    self.camera.read(addr)
+                } else {
+                    self.ram[self.ram_selection as usize & 0xf][addr as usize - 0xa000]
+                }
+            }
+            _ => panic!("Illegal cartridge read at {addr:#x}"),
+        }
+    }
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            x if (0xa000..0xc000).contains(&x) => {
+                if self.ram_selection & 0x10 != 0 {
+                    self.camera.write(addr, val);
+                } else if self.ram_enable {
+                    self.ram[self.ram_selection as usize & 0xf][addr as usize - 0xa000] = val;
+                    mark_dirty(&mut self.save);
+                }
+            }
+            x if x < 0x2000 => {
+                self.ram_enable = val & 0xf == 0xa;
+                if !self.ram_enable {
+                    report_save(self.save());
+                }
+            }
+            x if x < 0x4000 => {
+                self.rom_selection = ((val & 0x3f) as usize % self.banks.len()) as u8;
+            }
+            x if x < 0x6000 => self.ram_selection = val & 0x1f,
+            x if x < 0x8000 => {}
+            _ => {
+                panic!("Illegal cartridge write at {addr:#x}")
+            }
+        };
+    }
+
+    fn tick(&mut self) {
+        if self.camera.tick() {
+            let image = self.camera.image();
+            self.ram[0][0x100..0x100 + image.len()].copy_from_slice(&image);
+            mark_dirty(&mut self.save);
+        }
+    }
+
+    fn save(&mut self) -> Result<()> {
+        match &mut self.save {
+            Some(save) => save.flush(self.ram.as_flattened()),
+            None => Ok(()),
+        }
+    }
+}
+
+// Bandai TAMA5, only reachable through a register select at 0xa001 and a data
+// nibble at 0xa000. The 32 bytes of RAM of the TAMA6 are emulated, the clock
+// and alarm commands are ignored.
+struct Tama5 {
+    banks: Vec<[u8; 0x4000]>,
+    ram: [u8; 0x20],
+    save: Option<BatterySave>,
+    reg: u8,
+    regs: [u8; 0x10],
+}
+
+impl Tama5 {
+    pub fn new(rom: &mut Rom) -> Result<Self> {
+        let banks = rom_banks(rom)?;
+        let mut ram = [0u8; 0x20];
+        let (save, _) = open_battery(rom, slice::from_mut(&mut ram))?;
+        Ok(Tama5 {
+            banks,
+            ram,
+            save: Some(save),
+            reg: 0,
+            regs: [0; 0x10],
+        })
+    }
+
+    fn get_rom_bank(&self) -> usize {
+        ((self.regs[1] as usize & 1) << 4 | self.regs[0] as usize) % self.banks.len()
+    }
+
+    fn address(&self) -> usize {
+        (self.regs[6] as usize & 1) << 4 | self.regs[7] as usize
+    }
+
+    // Writing the low address nibble runs the command in the high one
+    fn run_command(&mut self) {
+        match self.regs[6] >> 1 {
+            0 => {
+                self.ram[self.address()] = self.regs[5] << 4 | self.regs[4];
+                mark_dirty(&mut self.save);
+            }
+            1 => {
+                let val = self.ram[self.address()];
+                self.regs[0xc] = val & 0xf;
+                self.regs[0xd] = val >> 4;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Drop for Tama5 {
+    fn drop(&mut self) {
+        report_save(self.save());
+    }
+}
+
+impl Cartridge for Tama5 {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            x if x < 0x4000 => self.banks[0][addr as usize],
+            x if x < 0x8000 => self.banks[self.get_rom_bank()][addr as usize - 0x4000],
+            0xa000 => match self.reg {
+                0xa => 0xf1, // ready
+                0xc | 0xd => 0xf0 | self.regs[self.reg as usize],
+                _ => 0xff,
+            },
+            x if (0xa000..0xc000).contains(&x) => 0xff,
+            _ => panic!("Illegal cartridge read at {addr:#x}"),
+        }
+    }
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xa000 => {
+                self.regs[self.reg as usize] = val & 0xf;
+                if self.reg == 7 {
+                    self.run_command();
+                }
+            }
+            0xa001 => self.reg = val & 0xf,
+            x if x < 0x8000 || (0xa000..0xc000).contains(&x) => {}
+            _ => {
+                panic!("Illegal cartridge write at {addr:#x}")
+            }
+        };
+    }
+
+    fn save(&mut self) -> Result<()> {
+        match &mut self.save {
+            Some(save) => save.flush(&self.ram),
+            None => Ok(()),
+        }
+    }
+}
+
+// MBC2 has 512 half-bytes of RAM mirrored over 0xa000-0xbfff, stored as one
+// byte each with the upper bits set like the console reads them
+fn pack_mbc2_ram(ram: &mut [u8]) {
+    for x in ram.iter_mut() {
+        *x |= 0xf0;
+    }
+}
+
+// Copy a raw RAM dump, optionally followed by an RTC footer, to the save of a
+// ROM. The previous save is kept as a backup.
+pub fn import_save(rom_path: &str, dump_path: &str, config: &Config) -> Result<()> {
+    let mut rom = Rom::new(rom_path, config)?;
+    let ram_size = rom.battery_ram_size()?.context("The cartridge has no battery")?;
+    let mut data = fs::read(dump_path).with_context(|| format!("Cannot read {dump_path}"))?;
+    if data.len() < ram_size {
+        eprintln!("Warning: {dump_path} is {} bytes, padded to the {ram_size} bytes of RAM", data.len());
+        data.resize(ram_size, 0);
+    }
+    let mbc_type = rom.get_mbc_type()?;
+    if let MbcType::Mbc2Battery = mbc_type {
+        pack_mbc2_ram(&mut data[..ram_size]);
+    }
+    let (mut save, current) = BatterySave::open(rom.save_path(), &rom.legacy_save_path())?;
+    // Dumps of the MBC6 SRAM alone keep the flash of the current save
+    if let (MbcType::Mbc6 | MbcType::Mbc6All, Some(current)) = (mbc_type, current) {
+        if data.len() == ram_size {
+            data.extend_from_slice(current.get(ram_size..).unwrap_or_default());
+        }
+    }
+    save.mark_dirty();
+    save.flush(&data)
+}
+
+// Write the RAM of the save of a ROM without the RTC footer, like flash
+// carts expect it
+pub fn export_save(rom_path: &str, dump_path: &str, config: &Config) -> Result<()> {
+    let mut rom = Rom::new(rom_path, config)?;
+    let ram_size = rom.battery_ram_size()?.context("The cartridge has no battery")?;
+    let (_, data) = BatterySave::open(rom.save_path(), &rom.legacy_save_path())?;
+    let mut data = data.context("The game has no save")?;
+    data.resize(ram_size, 0);
+    fs::write(dump_path, &data[..ram_size]).with_context(|| format!("Cannot write {dump_path}"))?;
+    println!("RAM exported to {dump_path}");
+    Ok(())
+}
+
+// Split the ROM in banks, sized from the header or the file if it is bigger
+fn rom_banks<const N: usize>(rom: &Rom) -> Result<Vec<[u8; N]>> {
+    let size = (rom.get_rom_size() * 0x400).max(rom.get_data_len()).next_power_of_two();
+    let mut banks = vec![[0xff; N]; size / N];
+    for (bank, chunk) in banks.iter_mut().zip(rom.read_range(0, rom.get_data_len())?.chunks(N)) {
+        bank[..chunk.len()].copy_from_slice(chunk);
+    }
+    Ok(banks)
+}
+
+// Number of 8 KiB RAM banks from the header, smaller RAM still takes a bank
+fn ram_banks(rom: &Rom, mapper: &str, max: usize) -> usize {
+    let banks = header_ram_banks(rom);
+    if banks > max {
+        eprintln!("Warning: {mapper} addresses {}KiB of RAM at most", max * 8);
+    }
+    banks.min(max)
+}
+
+fn header_ram_banks(rom: &Rom) -> usize {
+    rom.get_ram_size().div_ceil(0x2000)
+}
+
+// Open the save of a battery backed cartridge and fill the RAM from it,
+// returns what follows the RAM like a clock
+fn open_battery<const N: usize>(rom: &Rom, ram: &mut [[u8; N]]) -> Result<(BatterySave, Vec<u8>)> {
+    let (save, data) = BatterySave::open(rom.save_path(), &rom.legacy_save_path())?;
+    let extra = match data {
+        Some(data) => data[load_ram(ram, &data)..].to_vec(),
+        None => vec![],
+    };
+    Ok((save, extra))
+}
+
+// Fill the RAM banks from the start of a save, returns the size read. A
+// short save leaves the rest of the RAM blank.
+fn load_ram<const N: usize>(ram: &mut [[u8; N]], data: &[u8]) -> usize {
+    let size = (ram.len() * N).min(data.len());
+    ram.as_flattened_mut()[..size].copy_from_slice(&data[..size]);
+    size
+}
+
+fn mark_dirty(save: &mut Option<BatterySave>) {
+    if let Some(save) = save {
+        save.mark_dirty();
+    }
+}
+
+fn report_save(res: Result<()>) {
+    if let Err(e) = res {
+        eprintln!("Game save failed: {e:#}");
+    }
+}
+
+
+#[derive(TryFromPrimitive, Debug)]
+#[repr(u8)]
+enum MbcType {
+    NRom = 0x0,
+    Mbc1 = 0x1,
+    Mbc1Ram = 0x2,
+    Mbc1RamBattery = 0x3,
+    Mbc2 = 0x5,
+    Mbc2Battery = 0x6,
+    RomRam = 0x8,
+    RomRamBattery = 0x9,
+    Mmm01 = 0xb,
+    Mmm01Ram = 0xc,
+    Mmm01RamBattery = 0xd,
+    Mbc3TimerBattery = 0xF,
+    Mbc3TimerRamBattery = 0x10,
+    Mbc3 = 0x11,
+    Mbc3Ram = 0x12,
+    Mbc3RamBattery = 0x13,
+    Mbc5 = 0x19,
+    Mbc5Ram = 0x1a,
+    Mbc5RamBattery = 0x1b,
+    Mbc5Rumble = 0x1c,
+    Mbc5RumbleRam = 0x1d,
+    Mbc5RumbleRamBattery = 0x1e,
+    Mbc6 = 0x20,
+    Mbc6All = 0x21,
+    Mbc7SensorRumbleRamBattery = 0x22,
+    PocketCamera = 0xfc,
+    Tama5 = 0xfd,
+    HuC3 = 0xfe,
+    HuC1RamBattery = 0xff,
+}
+
+pub const NINTENDO_LOGO: [u8; 0x30] = [
+    0xce, 0xed, 0x66, 0x66, 0xcc, 0x0d, 0x00, 0x0b, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0c, 0x00, 0x0d,
+    0x00, 0x08, 0x11, 0x1f, 0x88, 0x89, 0x00, 0x0e, 0xdc, 0xcc, 0x6e, 0xe6, 0xdd, 0xdd, 0xd9, 0x99,
+    0xbb, 0xbb, 0x67, 0x63, 0x6e, 0x0e, 0xec, 0xcc, 0xdd, 0xdc, 0x99, 0x9f, 0xbb, 0xb9, 0x33, 0x3e,
+];
+
+// Banks addressed by MBC1 and MBC3
+const MBC_RAM_BANKS: usize = 4;
+
+// Header codes at 0x149, in bytes
+const RAM_SIZES: [(u8, usize); 6] =
+    [(0, 0), (1, 0x800), (2, 0x2000), (3, 0x8000), (4, 0x20000), (5, 0x10000)];
+
+// Header codes at 0x148, in KiB
+const ROM_SIZES: [(u8, usize); 12] = [
+    (0, 0x20),
+    (1, 0x40),
+    (2, 0x80),
+    (3, 0x100),
+    (4, 0x200),
+    (5, 0x400),
+    (6, 0x800),
+    (7, 0x1000),
+    (8, 0x2000),
+    (0x52, 0x480),
+    (0x53, 0x500),
+    (0x54, 0x600),
+];
+
+struct Rom {
+    data: Vec<u8>,
+    path: PathBuf,
+    save_dir: Option<PathBuf>,
+}
+
+impl Rom {
+    fn new(path: &str, config: &Config) -> Result<Self> {
+        let (buf, path) = archive::read(path, config.zip_entry.as_deref())?;
+        Rom::from_data(buf, path, config)
+    }
+
+    // The path is the one of the ROM even if it came from an archive
+    fn from_data(buf: Vec<u8>, path: PathBuf, config: &Config) -> Result<Self> {
+        if buf.len() < 0x150 {
+            bail!("{} is too small to be a ROM: {} bytes", path.display(), buf.len());
+        }
+        Ok(Rom {
+            data: buf,
+            path,
+            save_dir: config.save_dir.clone(),
+        })
+    }
+
+    // Invalid characters are replaced, validate reports them
+    fn get_title(&self) -> String {
+        let title = String::from_utf8_lossy(&self.data[0x134..0x143]);
+        title.trim_matches(char::from(0)).to_owned()
+    }
+
+    // Problems that do not prevent running the ROM
+    fn validate(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        let base = self.header_base();
+        let header = &self.data[base..base + 0x150];
+        if header[0x104..0x134] != NINTENDO_LOGO {
+            warnings.push("Nintendo logo mismatch, the console would lock up".to_owned());
+        }
+        let checksum = header[0x134..0x14d]
+            .iter()
+            .fold(0u8, |acc, x| acc.wrapping_sub(*x).wrapping_sub(1));
+        if checksum != header[0x14d] {
+            warnings.push(format!(
+                "Header checksum is {:#04x}, expected {checksum:#04x}, the console would lock up",
+                header[0x14d]
+            ));
+        }
+        let global = self.data[base..]
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !(0x14e..0x150).contains(i))
+            .fold(0u16, |acc, (_, x)| acc.wrapping_add(*x as u16));
+        let expected = u16::from_be_bytes([header[0x14e], header[0x14f]]);
+        if global != expected && base == 0 {
+            warnings.push(format!("Global checksum is {expected:#06x}, computed {global:#06x}"));
+        }
+        if str::from_utf8(&self.data[0x134..0x143]).is_err() {
+            warnings.push(format!("Title is not valid UTF-8, shown as {:?}", self.get_title()));
+        }
+        if !ROM_SIZES.iter().any(|(code, _)| *code == self.data[0x148]) {
+            warnings.push(format!(
+                "Unknown ROM size code {:#04x}, using {}KiB from the file size",
+                self.data[0x148],
+                self.get_rom_size()
+            ));
+        } else if self.get_rom_size() * 0x400 != self.data.len() && base == 0 {
+            warnings.push(format!(
+                "The header declares {}KiB of ROM, the file is {} bytes",
+                self.get_rom_size(),
+                self.data.len()
+            ));
+        }
+        if !RAM_SIZES.iter().any(|(code, _)| *code == header[0x149]) {
+            warnings.push(format!(
+                "Unknown RAM size code {:#04x}, using {}KiB",
+                header[0x149],
+                self.get_ram_size() / 0x400
+            ));
+        }
+        warnings
+    }
+
+    fn is_gbc(&self) -> bool {
+        self.data[0x143] & 0x80 != 0
+    }
+
+    // The SGB BIOS also requires the new licensee code
+    fn is_sgb(&self) -> bool {
+        self.data[0x146] == 0x03 && self.data[0x14b] == 0x33
+    }
+
+    fn is_nintendo_licensee(&self) -> bool {
+        match self.data[0x14b] {
+            0x01 => true,
+            0x33 => &self.data[0x144..0x146] == b"01",
+            _ => false,
+        }
+    }
+
+    // MMM01 dumps have the menu last, with the header of the cartridge. The
+    // header at the start is the one of the first game.
+    fn header_base(&self) -> usize {
+        let menu = self.data.len().saturating_sub(0x8000);
+        match self.data.get(menu + 0x147) {
+            Some(0xb..=0xd) if menu > 0 => menu,
+            _ => 0,
+        }
+    }
+
+    // In bytes, the largest common size if the code is unknown
+    fn get_ram_size(&self) -> usize {
+        let code = self.data[self.header_base() + 0x149];
+        RAM_SIZES.iter().find(|(c, _)| *c == code).map_or(0x8000, |(_, size)| *size)
+    }
+
+    // In KiB, from the file size if the code is unknown
+    fn get_rom_size(&self) -> usize {
+        let code = self.data[0x148];
+        match ROM_SIZES.iter().find(|(c, _)| *c == code) {
+            Some((_, size)) => *size,
+            None => (self.data.len().next_power_of_two() / 0x400).max(0x20),
+        }
+    }
+
+    fn get_mbc_type(&mut self) -> Result<MbcType> {
+        let mbc_code = self.data[self.header_base() + 0x147];
+        MbcType::try_from(mbc_code).context(format!("Unsuported MBC: {mbc_code:x}"))
+    }
+
+    // MBC1M boards are 1 MiB with a menu and the games at each 256 KiB
+    // boundary, each with its own header
+    fn is_mbc1_multicart(&self) -> bool {
+        if self.data.len() != 0x100000 {
+            return false;
+        }
+        (1..4).any(|game| {
+            let logo = game * 0x40000 + 0x104;
+            self.data[logo..logo + 0x30] == NINTENDO_LOGO
+        })
+    }
+
+    // <rom>.sav, next to the ROM or in the save directory
+    fn save_path(&self) -> PathBuf {
+        let path = self.path.with_extension("sav");
+        match (&self.save_dir, path.file_name()) {
+            (Some(dir), Some(name)) => dir.join(name),
+            _ => path,
+        }
+    }
+
+    // Written by previous versions
+    fn legacy_save_path(&self) -> PathBuf {
+        self.path.with_extension("save")
+    }
+
+    // Size of the RAM in save files, without the clock, None without battery
+    fn battery_ram_size(&mut self) -> Result<Option<usize>> {
+        Ok(match self.get_mbc_type()? {
+            MbcType::Mbc2Battery => Some(0x200),
+            MbcType::RomRamBattery => Some(self.get_ram_size().min(0x2000)),
+            MbcType::Mbc6 | MbcType::Mbc6All => Some(0x8000),
+            MbcType::Mbc7SensorRumbleRamBattery => Some(0x100),
+            MbcType::PocketCamera => Some(0x20000),
+            MbcType::Tama5 => Some(0x20),
+            // Same banks as the mappers allocate
+            MbcType::Mbc1RamBattery
+            | MbcType::Mbc3TimerBattery
+            | MbcType::Mbc3TimerRamBattery
+            | MbcType::Mbc3RamBattery => Some(header_ram_banks(self).min(MBC_RAM_BANKS) * 0x2000),
+            MbcType::Mmm01RamBattery | MbcType::HuC3 | MbcType::HuC1RamBattery => {
+                Some(header_ram_banks(self) * 0x2000)
+            }
+            _ => None,
+        })
+    }
+
+    fn get_data_len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn read_range(&self, begin: usize, end: usize) -> Result<&[u8]> {
+        if self.data.len() < end {
+            bail!("Load ROM out-of-bound read. Maybe the file is corrupted ?")
+        }
+        Ok(&self.data[begin..end])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_dir::TestDir;
+
+    fn rom(banks: usize, mbc: u8) -> Rom {
+        let mut data = vec![0; banks * 0x4000];
+        for (i, bank) in data.chunks_mut(0x4000).enumerate() {
+            bank[0x1000] = i as u8;
+        }
+        data[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+        data[0x134..0x138].copy_from_slice(b"TEST");
+        data[0x147] = mbc;
+        data[0x148] = (banks / 2).trailing_zeros() as u8;
+        data[0x14d] = data[0x134..0x14d].iter().fold(0u8, |acc, x| acc.wrapping_sub(*x + 1));
+        let global = data.iter().fold(0u16, |acc, x| acc.wrapping_add(*x as u16));
+        data[0x14e..0x150].copy_from_slice(&global.to_be_bytes());
+        Rom { data, path: PathBuf::from("test.gb"), save_dir: None }
+    }
+
+    #[test]
+    fn header_warnings() {
+        let mut rom = rom(2, 0);
+        assert!(rom.validate().is_empty());
+        rom.data[0x134] = 0xff;
+        rom.data[0x148] = 0x20;
+        rom.data.truncate(0x6000);
+        let warnings = rom.validate();
+        assert_eq!(warnings.len(), 4, "{warnings:?}"); // both checksums, title and size
+        assert_eq!(rom.get_title(), "\u{fffd}EST");
+        assert_eq!(rom.get_rom_size(), 0x20);
+        let mut cart = NRom::new(&mut rom, false).unwrap();
+        assert_eq!(cart.read(0x7fff), 0xff);
+        assert_eq!(cart.read(0xa000), 0);
+        cart.write(0xa000, 1);
+    }
+
+    #[test]
+    fn mbc1_banking() {
+        let mut rom = rom(64, 1);
+        // A bigger file than the header does not overflow the banks
+        rom.data.extend_from_slice(&[0; 0x4000]);
+        let mut cart = MBC1::new(&mut rom, false, false).unwrap();
+        cart.write(0x2000, 0);
+        assert_eq!(cart.read(0x5000), 1);
+        cart.write(0x4000, 1);
+        assert_eq!(cart.read(0x5000), 0x21);
+        assert_eq!(cart.read(0x1000), 0);
+        cart.write(0x6000, 1);
+        assert_eq!(cart.read(0x1000), 0x20);
+
+        let mut cart = MBC1::new(&mut rom, false, true).unwrap();
+        cart.write(0x2000, 0x12);
+        cart.write(0x4000, 3);
+        assert_eq!(cart.read(0x5000), 0x32);
+    }
+
+    #[test]
+    fn oversized_header_bank_select() {
+        // 4 MiB in the header, 256 banks mostly past the end of the file
+        let mut rom = rom(2, 0x11);
+        rom.data[0x148] = 0x07;
+        let mut cart = MBC3::new(&mut rom, false, None).unwrap();
+        cart.write(0x2000, 1);
+        assert_eq!(cart.read(0x5000), 1);
+        cart.write(0x2000, 0x7f);
+        assert_eq!(cart.read(0x5000), 0xff);
+    }
+
+    #[test]
+    fn save_sizes_match_the_ram() {
+        // 2 KiB in the header still takes a whole bank
+        let mut rom = rom(2, 0x03);
+        rom.data[0x149] = 1;
+        assert_eq!(rom.battery_ram_size().unwrap(), Some(0x2000));
+        rom.data[0x149] = 4;
+        assert_eq!(rom.battery_ram_size().unwrap(), Some(MBC_RAM_BANKS * 0x2000));
+
+        // Short saves leave the rest blank, what follows the RAM is returned
+        let mut ram = [[0xffu8; 4]; 2];
+        assert_eq!(load_ram(&mut ram, &[1, 2, 3]), 3);
+        assert_eq!(ram, [[1, 2, 3, 0xff], [0xff; 4]]);
+        assert_eq!(load_ram(&mut ram, &[0; 10]), 8);
+    }
+
+    #[test]
+    fn import_keeps_the_footer() {
+        let dir = TestDir::new("import");
+        let mut rom = rom(2, 0x10); // MBC3 with clock, RAM and battery
+        rom.data[0x149] = 2;
+        let rom_path = dir.join("game.gb");
+        fs::write(&rom_path, &rom.data).unwrap();
+        let config = Config { save_dir: Some(dir.to_path_buf()), ..Default::default() };
+
+        let mut dump = vec![0x55; 0x2000];
+        dump.extend([0xaa; 48]);
+        let dump_path = dir.join("dump.sav");
+        fs::write(&dump_path, &dump).unwrap();
+        import_save(rom_path.to_str().unwrap(), dump_path.to_str().unwrap(), &config).unwrap();
+        assert_eq!(fs::read(dir.join("game.sav")).unwrap(), dump);
+
+        // A short dump is padded, without a footer
+        fs::write(&dump_path, [0x55; 0x1000]).unwrap();
+        import_save(rom_path.to_str().unwrap(), dump_path.to_str().unwrap(), &config).unwrap();
+        let save = fs::read(dir.join("game.sav")).unwrap();
+        assert_eq!(save.len(), 0x2000);
+        assert_eq!(save[0x1000..], [0; 0x1000]);
     }
 }
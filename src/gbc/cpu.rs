@@ -1,6 +1,9 @@
 use super::bus::*;
+use super::EmuError;
+use super::model::Model;
 
 pub struct Cpu {
+    model: Model,
     a: u8,
     b: u8,
     c: u8,
@@ -18,7 +21,11 @@ pub struct Cpu {
 
     wait: i32,
     interrupts_enabled: bool,
+    ei_pending: bool, // EI only takes effect after the next instruction
     halted: bool,
+    halt_bug: bool, // next opcode fetch does not increment pc
+    locked_up: bool,
+    error: Option<EmuError>,
 }
 
 const ZERO: u8 = 7;
@@ -27,8 +34,9 @@ const HALFCARRY: u8 = 5;
 const CARRY: u8 = 4;
 
 impl Cpu {
-    pub fn new() -> Self {
+    pub fn new(model: Model) -> Self {
         Cpu {
+            model,
             a: 0,
             b: 0,
             c: 0,
@@ -36,8 +44,8 @@ impl Cpu {
             e: 0,
             h: 0,
             l: 0,
-            pc: 0x100,
-            sp: 0xfffe,
+            pc: 0,
+            sp: 0,
 
             zerof: 0,
             add_subf: 0,
@@ -46,7 +54,11 @@ impl Cpu {
 
             wait: 0,
             interrupts_enabled: false,
+            ei_pending: false,
             halted: false,
+            halt_bug: false,
+            locked_up: false,
+            error: None,
         }
     }
 
@@ -58,14 +70,47 @@ impl Cpu {
         self.e = 0;
         self.h = 0;
         self.l = 0;
-        self.pc = 0x100;
-        self.sp = 0xfffe;
+        self.pc = 0;
+        self.sp = 0;
 
         self.zerof = 0;
         self.add_subf = 0;
         self.half_carryf = 0;
         self.carryf = 0;
         self.interrupts_enabled = false;
+        self.ei_pending = false;
+        self.halted = false;
+        self.halt_bug = false;
+        self.locked_up = false;
+        self.error = None;
+    }
+
+    // Register state left by the boot ROM when it jumps to the cartridge
+    pub fn skip_boot(&mut self, gbc_cartridge: bool) {
+        let (a, f, b, c, d, e, h, l) = match self.model {
+            Model::Dmg => (0x01, 0xb0, 0x00, 0x13, 0x00, 0xd8, 0x01, 0x4d),
+            Model::Mgb => (0xff, 0xb0, 0x00, 0x13, 0x00, 0xd8, 0x01, 0x4d),
+            Model::Sgb => (0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xc0, 0x60),
+            Model::Cgb if gbc_cartridge => (0x11, 0x80, 0x00, 0x00, 0xff, 0x56, 0x00, 0x0d),
+            Model::Cgb => (0x11, 0x80, 0x00, 0x00, 0x00, 0x08, 0x00, 0x7c),
+            Model::Agb if gbc_cartridge => (0x11, 0x00, 0x01, 0x00, 0xff, 0x56, 0x00, 0x0d),
+            Model::Agb => (0x11, 0x00, 0x01, 0x00, 0x00, 0x08, 0x00, 0x7c),
+        };
+        self.a = a;
+        self.set_flags(f);
+        self.b = b;
+        self.c = c;
+        self.d = d;
+        self.e = e;
+        self.h = h;
+        self.l = l;
+        self.pc = 0x100;
+        self.sp = 0xfffe;
+    }
+
+    // Error raised since the last call, if any
+    pub fn take_error(&mut self) -> Option<EmuError> {
+        self.error.take()
     }
 
     fn flags(&self) -> u8 {
@@ -98,6 +143,39 @@ impl Cpu {
         self.wait = 16;
     }
 
+    // The high byte of pc is pushed before the interrupt vector is chosen, so
+    // a push that overwrites IE can cancel the dispatch (pc is then set to 0).
+    fn dispatch_interrupt(&mut self, bus: &mut Bus) {
+        self.interrupts_enabled = false;
+        self.wait = 20;
+
+        self.sp = u16::wrapping_sub(self.sp, 1);
+        bus.write(self.sp, (self.pc >> 8) as u8);
+        let active_interrupts = bus.enabled_interrupts & bus.requested_interrupts & 0x1f;
+        self.sp = u16::wrapping_sub(self.sp, 1);
+        bus.write(self.sp, self.pc as u8);
+
+        //println!("Interrupt {:x} at pc={:#x}", active_interrupts, self.pc);
+        self.pc = if active_interrupts & VBLANK != 0 {
+            bus.requested_interrupts &= !VBLANK;
+            0x40
+        } else if active_interrupts & LCD_STAT != 0 {
+            bus.requested_interrupts &= !LCD_STAT;
+            0x48
+        } else if active_interrupts & TIMER != 0 {
+            bus.requested_interrupts &= !TIMER;
+            0x50
+        } else if active_interrupts & SERIAL != 0 {
+            bus.requested_interrupts &= !SERIAL;
+            0x58
+        } else if active_interrupts & JOYPAD != 0 {
+            bus.requested_interrupts &= !JOYPAD;
+            0x60
+        } else {
+            0x0000
+        };
+    }
+
     #[allow(clippy::collapsible_else_if)]
     #[allow(unused_parens)]
     pub fn tick(&mut self, bus: &mut Bus) {
@@ -106,41 +184,33 @@ impl Cpu {
             return;
         }
 
-        if self.halted{
-            if bus.requested_interrupts != 0 {
-                self.pc += 1;
-                self.halted = false;
-            } else {
+        if self.locked_up {
+            // Only a reset gets the CPU out of this state, interrupts are ignored
+            self.wait = 4;
+            return;
+        }
+
+        if self.halted {
+            if bus.enabled_interrupts & bus.requested_interrupts & 0x1f == 0 {
+                self.wait = 4;
                 return;
             }
-        } 
+            // Waking up from HALT takes one more M-cycle
+            self.halted = false;
+            self.wait = 4;
+            return;
+        }
 
-        if self.interrupts_enabled && (bus.enabled_interrupts & bus.requested_interrupts != 0) {
-            let active_interrupts = bus.enabled_interrupts & bus.requested_interrupts;
-            self.sp -= 2;
-            self.interrupts_enabled = false;
-            bus.write16(self.sp, self.pc);
-            self.wait = 20;
-            //println!("Interrupt {:x} at pc={:#x}", active_interrupts, self.pc);
-            if active_interrupts & VBLANK != 0 {
-                self.pc = 0x40;
-                bus.requested_interrupts &= !VBLANK;
-            } else if active_interrupts & LCD_STAT != 0 {
-                self.pc = 0x48;
-                bus.requested_interrupts &= !LCD_STAT;
-            } else if active_interrupts & TIMER != 0 {
-                self.pc = 0x50;
-                bus.requested_interrupts &= !TIMER;
-            } else if active_interrupts & SERIAL != 0 {
-                self.pc = 0x58;
-                bus.requested_interrupts &= !SERIAL;
-            } else if active_interrupts & JOYPAD != 0 {
-                self.pc = 0x60;
-                bus.requested_interrupts &= !JOYPAD;
-            }
+        if self.interrupts_enabled && (bus.enabled_interrupts & bus.requested_interrupts & 0x1f != 0) {
+            self.dispatch_interrupt(bus);
             return;
         }
 
+        if self.ei_pending {
+            self.ei_pending = false;
+            self.interrupts_enabled = true;
+        }
+
         macro_rules! disasm {
             ($($arg:tt)+) => (
                 #[cfg(feature = "disasm")] 
@@ -373,6 +443,11 @@ impl Cpu {
         }
 
         let op = bus.read(self.pc);
+        if self.halt_bug {
+            // The opcode byte is fetched again as the first operand byte
+            self.halt_bug = false;
+            self.pc = u16::wrapping_sub(self.pc, 1);
+        }
         match op {
             0x0 => {
                 self.wait = 4;
@@ -1500,11 +1575,14 @@ impl Cpu {
             0xF3 => {
                 self.wait = 4;
                 self.interrupts_enabled = false;
+                self.ei_pending = false;
                 disasm!("DI");
             }
             0xfb => {
                 self.wait = 4;
-                self.interrupts_enabled = true;
+                if !self.interrupts_enabled {
+                    self.ei_pending = true;
+                }
                 disasm!("EI");
             }
             0xe8 => {
@@ -1585,12 +1663,14 @@ impl Cpu {
             0x76 => {
                 // HALT
                 self.wait = 4;
-                self.halted = true;
                 disasm!("HALT");
-                if self.interrupts_enabled {
-                    self.pc -= 1;
+                if !self.interrupts_enabled
+                    && bus.enabled_interrupts & bus.requested_interrupts & 0x1f != 0
+                {
+                    // HALT bug: the CPU does not halt and fails to increment pc
+                    self.halt_bug = true;
                 } else {
-                    self.wait = 8;
+                    self.halted = true;
                 }
             }
             0x10 => {
@@ -1629,8 +1709,12 @@ impl Cpu {
                 self.pc += 1;
                 self.cb_ext(bus);
             }
-            _ => {
-                eprintln!("Unknown opcode at 0x{:x} : 0x{:x}", self.pc, op);
+            0xd3 | 0xdb | 0xdd | 0xe3 | 0xe4 | 0xeb | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => {
+                disasm!("ILLEGAL {:#x}", op);
+                self.wait = 4;
+                self.locked_up = true;
+                self.error = Some(EmuError::IllegalOpcode { pc: self.pc, opcode: op });
+                return;
             }
         };
         self.pc = u16::wrapping_add(self.pc, 1);
@@ -1691,7 +1775,7 @@ impl Cpu {
                         bus.write(hl, self.$operation(bus.read(hl), ($opcode & 0x38) >> 3));
                         disasm!("{} (HL), {}", stringify!($operation), ($opcode & 0x38) >> 3);
                     }
-                    _ => panic!("Invalid cb submatch {:#x}", $opcode),
+                    _ => unreachable!(),
                 }
             };
         }
@@ -2036,7 +2120,7 @@ impl Cpu {
             x if x & 0xc0 == 0xC0 => {
                 sub_match!(x, set);
             }
-            _ => eprintln!("{:#x}: CB not supported : {:#x}", self.pc, op),
+            _ => unreachable!(),
         }
     }
 
@@ -0,0 +1,195 @@
+// 93LC56 serial EEPROM of the MBC7: 128 words of 16 bits, driven bit by bit
+// through chip select, clock and data lines. Writes complete instantly, the
+// chip always reports itself ready.
+
+pub struct Eeprom {
+    pub data: [u8; 0x100], // little endian words, like the save files of other emulators
+    cs: bool,
+    clk: bool,
+    di: bool,
+    do_: bool,
+    write_enable: bool,
+    state: State,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Idle,
+    // Start bit, opcode and address, shifted in MSB first
+    Command { bits: u8, value: u16 },
+    // Data for WRITE and WRAL
+    Data { command: u16, bits: u8, value: u16 },
+    Read { bits: u8, value: u16, addr: u8 },
+}
+
+impl Eeprom {
+    pub fn new() -> Self {
+        Eeprom {
+            data: [0xff; 0x100],
+            cs: false,
+            clk: false,
+            di: false,
+            do_: true,
+            write_enable: false,
+            state: State::Idle,
+        }
+    }
+
+    // Bit 7 CS, bit 6 CLK, bit 1 DI, bit 0 DO
+    pub fn read(&self) -> u8 {
+        (self.cs as u8) << 7 | (self.clk as u8) << 6 | (self.di as u8) << 1 | self.do_ as u8
+    }
+
+    pub fn write(&mut self, val: u8) {
+        let cs = val & 0x80 != 0;
+        let clk = val & 0x40 != 0;
+        self.di = val & 0x02 != 0;
+        if !cs {
+            self.state = State::Idle;
+            self.do_ = true;
+        } else if clk && !self.clk {
+            self.clock();
+        }
+        self.cs = cs;
+        self.clk = clk;
+    }
+
+    fn clock(&mut self) {
+        let bit = self.di as u16;
+        self.state = match self.state {
+            // Leading zeros before the start bit are ignored
+            State::Idle if bit == 0 => State::Idle,
+            State::Idle => State::Command { bits: 1, value: 1 },
+            State::Command { bits, value } => {
+                let value = value << 1 | bit;
+                if bits + 1 < 11 {
+                    State::Command { bits: bits + 1, value }
+                } else {
+                    self.command(value & 0x3ff)
+                }
+            }
+            State::Data { command, bits, value } => {
+                let value = value << 1 | bit;
+                if bits + 1 < 16 {
+                    State::Data { command, bits: bits + 1, value }
+                } else {
+                    self.write_data(command, value);
+                    State::Idle
+                }
+            }
+            State::Read { bits, value, addr } => {
+                self.do_ = value & 0x8000 != 0;
+                if bits + 1 < 16 {
+                    State::Read { bits: bits + 1, value: value << 1, addr }
+                } else {
+                    // Sequential read continues with the next word
+                    let addr = (addr + 1) & 0x7f;
+                    State::Read { bits: 0, value: self.word(addr), addr }
+                }
+            }
+        };
+    }
+
+    // 2 bits opcode and 8 bits address, the highest address bit is unused
+    fn command(&mut self, command: u16) -> State {
+        let addr = (command & 0x7f) as u8;
+        match command >> 8 {
+            0b10 => {
+                self.do_ = false; // dummy bit before the data
+                State::Read { bits: 0, value: self.word(addr), addr }
+            }
+            0b01 => State::Data { command, bits: 0, value: 0 },
+            0b11 => {
+                if self.write_enable {
+                    self.set_word(addr, 0xffff);
+                }
+                self.do_ = true;
+                State::Idle
+            }
+            _ => match (command >> 6) & 0x3 {
+                0b00 => {
+                    self.write_enable = false;
+                    State::Idle
+                }
+                0b01 => State::Data { command, bits: 0, value: 0 },
+                0b10 => {
+                    if self.write_enable {
+                        self.data = [0xff; 0x100];
+                    }
+                    self.do_ = true;
+                    State::Idle
+                }
+                _ => {
+                    self.write_enable = true;
+                    State::Idle
+                }
+            },
+        }
+    }
+
+    fn write_data(&mut self, command: u16, value: u16) {
+        self.do_ = true;
+        if !self.write_enable {
+            return;
+        }
+        if command >> 8 == 0b01 {
+            self.set_word((command & 0x7f) as u8, value);
+        } else {
+            for addr in 0..0x80 {
+                self.set_word(addr, value);
+            }
+        }
+    }
+
+    fn word(&self, addr: u8) -> u16 {
+        let i = addr as usize * 2;
+        u16::from_le_bytes([self.data[i], self.data[i + 1]])
+    }
+
+    fn set_word(&mut self, addr: u8, value: u16) {
+        let i = addr as usize * 2;
+        self.data[i..i + 2].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(eeprom: &mut Eeprom, bits: u32, len: u8) {
+        for i in (0..len).rev() {
+            let di = ((bits >> i) & 1) as u8 * 0x02;
+            eeprom.write(0x80 | di);
+            eeprom.write(0xc0 | di);
+        }
+    }
+
+    fn receive(eeprom: &mut Eeprom) -> u16 {
+        let mut value = 0;
+        for _ in 0..16 {
+            eeprom.write(0x80);
+            eeprom.write(0xc0);
+            value = value << 1 | (eeprom.read() & 1) as u16;
+        }
+        value
+    }
+
+    #[test]
+    fn write_needs_enable_then_reads_back() {
+        let mut eeprom = Eeprom::new();
+        send(&mut eeprom, 0b101_0000_0011 << 16 | 0x1234, 27); // WRITE 3
+        eeprom.write(0);
+        assert_eq!(eeprom.word(3), 0xffff);
+
+        send(&mut eeprom, 0b100_1100_0000, 11); // EWEN
+        eeprom.write(0);
+        send(&mut eeprom, 0b101_0000_0011 << 16 | 0x1234, 27);
+        eeprom.write(0);
+        assert_eq!(eeprom.data[6..8], [0x34, 0x12]);
+
+        send(&mut eeprom, 0b110_0000_0011, 11); // READ 3
+        assert_eq!(eeprom.read() & 1, 0);
+        assert_eq!(receive(&mut eeprom), 0x1234);
+        assert_eq!(receive(&mut eeprom), 0xffff);
+    }
+}
@@ -0,0 +1,203 @@
+// Game Boy Sound System rips: the sound driver of a game with a header giving
+// its load, init and play addresses. They are turned into a ROM image with a
+// small player program below the load address, which calls init with the
+// track number then play from the VBlank or timer interrupt.
+
+use anyhow::{bail, Result};
+
+use super::cartridge::NINTENDO_LOGO;
+
+const HEADER_SIZE: usize = 0x70;
+
+// Read by the player, served by the mapper
+pub const TRACK_ADDR: u16 = 0x00f0;
+// Set when another track is selected, cleared by writing to it
+pub const RESTART_ADDR: u16 = 0x00f1;
+
+const PLAYER_ADDR: usize = 0x150;
+
+pub struct GbsHeader {
+    pub tracks: u8,
+    pub first_track: u8, // from 0
+    load: u16,
+    init: u16,
+    play: u16,
+    sp: u16,
+    tma: u8,
+    tac: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+pub fn is_gbs(data: &[u8]) -> bool {
+    data.starts_with(b"GBS")
+}
+
+impl GbsHeader {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_SIZE || !is_gbs(data) {
+            bail!("Not a GBS file");
+        }
+        if data[3] != 1 {
+            bail!("Unsupported GBS version {}", data[3]);
+        }
+        let word = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        let text = |i: usize| {
+            let text = String::from_utf8_lossy(&data[i..i + 32]);
+            text.trim_end_matches(char::from(0)).to_owned()
+        };
+        let header = GbsHeader {
+            tracks: data[4],
+            first_track: data[5].saturating_sub(1),
+            load: word(6),
+            init: word(8),
+            play: word(10),
+            sp: word(12),
+            tma: data[14],
+            tac: data[15],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+        };
+        if header.tracks == 0 {
+            bail!("The GBS file has no tracks");
+        }
+        if !(0x400..0x8000).contains(&header.load) || header.init >= 0x8000 || header.play >= 0x8000 {
+            bail!("Invalid GBS addresses");
+        }
+        Ok(header)
+    }
+
+    // The code lands at the load address, banks are 16 KiB like with an MBC
+    pub fn image(&self, data: &[u8]) -> Vec<u8> {
+        let code = &data[HEADER_SIZE..];
+        let len = (self.load as usize + code.len()).max(0x8000).next_multiple_of(0x4000);
+        let mut image = vec![0xff; len];
+        image[self.load as usize..self.load as usize + code.len()].copy_from_slice(code);
+        image[..0x400].fill(0);
+
+        // RST vectors go to the same offset from the load address
+        for rst in (0..0x40).step_by(8) {
+            let [lo, hi] = (self.load + rst as u16).to_le_bytes();
+            image[rst..rst + 3].copy_from_slice(&[0xc3, lo, hi]); // jp
+        }
+        // Interrupts: VBlank and timer call play, the others return
+        let [play_lo, play_hi] = self.play.to_le_bytes();
+        for vector in (0x40..0x68).step_by(8) {
+            image[vector] = 0xd9; // reti
+        }
+        for vector in [0x40, 0x50] {
+            image[vector..vector + 4].copy_from_slice(&[0xcd, play_lo, play_hi, 0xd9]); // call play, reti
+        }
+
+        let [player_lo, player_hi] = (PLAYER_ADDR as u16).to_le_bytes();
+        image[0x100..0x104].copy_from_slice(&[0x00, 0xc3, player_lo, player_hi]); // nop, jp player
+        image[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+        image[0x134..0x144].copy_from_slice(&title_bytes(&self.title));
+        image[0x14d] = image[0x134..0x14d]
+            .iter()
+            .fold(0u8, |acc, x| acc.wrapping_sub(*x).wrapping_sub(1));
+
+        let player = self.player();
+        image[PLAYER_ADDR..PLAYER_ADDR + player.len()].copy_from_slice(&player);
+        image
+    }
+
+    // Plays the track in TRACK_ADDR, and starts over when RESTART_ADDR is set
+    #[rustfmt::skip]
+    fn player(&self) -> Vec<u8> {
+        let [sp_lo, sp_hi] = self.sp.to_le_bytes();
+        let [init_lo, init_hi] = self.init.to_le_bytes();
+        let [track_lo, track_hi] = TRACK_ADDR.to_le_bytes();
+        let [restart_lo, restart_hi] = RESTART_ADDR.to_le_bytes();
+        let [player_lo, player_hi] = (PLAYER_ADDR as u16).to_le_bytes();
+        // The timer drives play when enabled, VBlank otherwise
+        let interrupt = if self.tac & 0x04 != 0 { 0x04 } else { 0x01 };
+        vec![
+            0xf3,                       // di
+            0x31, sp_lo, sp_hi,         // ld sp, SP
+            0x21, 0x00, 0xa0,           // ld hl, 0xa000
+            0xaf,                       // clear: xor a
+            0x22,                       //   ld [hl+], a
+            0x7c,                       //   ld a, h
+            0xfe, 0xe0,                 //   cp 0xe0
+            0x20, 0xf9,                 //   jr nz, clear
+            0xaf,                       // xor a
+            0xe0, 0x26,                 // ldh [NR52], a (resets the APU)
+            0x3e, 0x80, 0xe0, 0x26,     // NR52 = 0x80
+            0x3e, 0x77, 0xe0, 0x24,     // NR50 = 0x77
+            0x3e, 0xff, 0xe0, 0x25,     // NR51 = 0xff
+            0x3e, self.tma, 0xe0, 0x06, // TMA
+            0x3e, self.tac, 0xe0, 0x07, // TAC
+            0x3e, interrupt, 0xe0, 0xff, // IE
+            0xaf,                       // xor a
+            0xe0, 0x0f,                 // ldh [IF], a
+            0xea, restart_lo, restart_hi, // ld [RESTART_ADDR], a
+            0xfa, track_lo, track_hi,   // ld a, [TRACK_ADDR]
+            0xcd, init_lo, init_hi,     // call init
+            0xfb,                       // ei
+            0x76,                       // idle: halt
+            0x00,                       //   nop
+            0xfa, restart_lo, restart_hi, // ld a, [RESTART_ADDR]
+            0xb7,                       //   or a
+            0x28, 0xf8,                 //   jr z, idle
+            0xc3, player_lo, player_hi, // jp player
+        ]
+    }
+}
+
+fn title_bytes(title: &str) -> [u8; 16] {
+    let mut res = [0; 16];
+    for (byte, c) in res.iter_mut().zip(title.bytes().take(15)) {
+        *byte = c;
+    }
+    res
+}
+
+pub struct Tracks {
+    pub count: u8,
+    pub current: u8,
+    pub restart: bool,
+}
+
+impl Tracks {
+    // Wraps around in both directions
+    pub fn select(&mut self, delta: i32) {
+        self.current = (self.current as i32 + delta).rem_euclid(self.count as i32) as u8;
+        self.restart = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_and_image() {
+        let mut data = vec![0; HEADER_SIZE];
+        data[..4].copy_from_slice(b"GBS\x01");
+        data[4] = 12;
+        data[5] = 3;
+        data[6..16].copy_from_slice(&[0x00, 0x04, 0x00, 0x04, 0x03, 0x04, 0xfe, 0xff, 0x00, 0x00]);
+        data[0x10..0x15].copy_from_slice(b"Title");
+        data.extend([0xc9, 0x00, 0x00, 0xc9]);
+        data.extend(vec![0x55; 0x8000]);
+
+        let header = GbsHeader::parse(&data).unwrap();
+        assert_eq!(header.first_track, 2);
+        assert_eq!(header.title, "Title");
+        let image = header.image(&data);
+        assert_eq!(image.len(), 0xc000);
+        assert_eq!(image[0x400..0x404], [0xc9, 0x00, 0x00, 0xc9]);
+        assert_eq!(image[0x08..0x0b], [0xc3, 0x08, 0x04]);
+        assert_eq!(image[0x40..0x44], [0xcd, 0x03, 0x04, 0xd9]);
+        assert_eq!(image[0x101..0x104], [0xc3, 0x50, 0x01]);
+        // VBlank drives play without the timer
+        let player = &image[PLAYER_ADDR..];
+        assert!(player.windows(4).any(|w| w == [0x3e, 0x01, 0xe0, 0xff]));
+
+        data[4] = 0;
+        assert!(GbsHeader::parse(&data).is_err());
+    }
+}
@@ -10,7 +10,19 @@ pub struct Joypad {
     key_le: KeyState,
     key_ri: KeyState,
 
-    show_directions: bool,
+    select: u8, // P14 and P15, active low
+    sgb: Option<SgbLink>,
+}
+
+// Command packets are sent to the SGB by pulsing P14 and P15: both low starts
+// a packet, then each bit is P14 low (0) or P15 low (1) followed by both high.
+// 128 bits are followed by a 0 stop bit.
+struct SgbLink {
+    packet: [u8; 16],
+    bit: usize,
+    receiving: bool,
+    players: u8,
+    player: u8,
 }
 
 impl Joypad {
@@ -25,7 +37,26 @@ impl Joypad {
             key_le: KeyState::Released,
             key_ri: KeyState::Released,
 
-            show_directions: false,
+            select: 0x30,
+            sgb: None,
+        }
+    }
+
+    pub fn enable_sgb(&mut self) {
+        self.sgb = Some(SgbLink {
+            packet: [0; 16],
+            bit: 0,
+            receiving: false,
+            players: 1,
+            player: 0,
+        });
+    }
+
+    // MLT_REQ, the keyboard and gamepads are player 1
+    pub fn set_players(&mut self, players: u8) {
+        if let Some(link) = &mut self.sgb {
+            link.players = players;
+            link.player = 0;
         }
     }
 
@@ -43,22 +74,64 @@ impl Joypad {
         }
     }
 
-    pub fn write(&mut self, val: u8) {
-        self.show_directions = !val & 0x10 != 0
+    // Returns a command packet once the SGB has received all of it
+    pub fn write(&mut self, val: u8) -> Option<[u8; 16]> {
+        let previous = self.select;
+        self.select = val & 0x30;
+        let link = self.sgb.as_mut()?;
+        match self.select {
+            0x00 => {
+                link.packet = [0; 16];
+                link.bit = 0;
+                link.receiving = true;
+            }
+            0x30 => {
+                // Leaving a selection switches to the next controller
+                if previous != 0x30 && link.players > 1 {
+                    link.player = (link.player + 1) % link.players;
+                }
+            }
+            _ if previous != 0x30 || !link.receiving => {}
+            pulse => {
+                let one = pulse == 0x10;
+                if link.bit == 128 {
+                    link.receiving = false;
+                    if !one {
+                        return Some(link.packet);
+                    }
+                } else {
+                    if one {
+                        link.packet[link.bit / 8] |= 1 << (link.bit % 8);
+                    }
+                    link.bit += 1;
+                }
+            }
+        }
+        None
     }
 
     pub fn read(&self) -> u8 {
-        if self.show_directions {
-            0x20 | self.key_dw.to_bit() << 3
+        let player = self.sgb.as_ref().map_or(0, |link| link.player);
+        if self.select == 0x30 {
+            return 0x30 | (0x0f - player);
+        }
+        let mut keys = 0x0f;
+        if player != 0 {
+            return self.select | keys;
+        }
+        if self.select & 0x10 == 0 {
+            keys &= self.key_dw.to_bit() << 3
                 | self.key_up.to_bit() << 2
                 | self.key_le.to_bit() << 1
-                | self.key_ri.to_bit()
-        } else {
-            0x10 | self.key_st.to_bit() << 3
+                | self.key_ri.to_bit();
+        }
+        if self.select & 0x20 == 0 {
+            keys &= self.key_st.to_bit() << 3
                 | self.key_se.to_bit() << 2
                 | self.key_b.to_bit() << 1
-                | self.key_a.to_bit()
+                | self.key_a.to_bit();
         }
+        self.select | keys
     }
 
     fn set_key_state(&mut self, key: GBKey, status: KeyState) {
@@ -0,0 +1,142 @@
+// Map of the 0xff00-0xff7f I/O area: which registers exist, which bits can
+// be written and which bits always read back as 1 (unused or write-only).
+
+#[derive(Clone, Copy)]
+pub struct IoRegister {
+    pub read_ones: u8,
+    pub write_mask: u8,
+    pub mapped: bool,
+    pub cgb_only: bool,
+}
+
+impl IoRegister {
+    const fn new(read_ones: u8, write_mask: u8) -> Self {
+        IoRegister {
+            read_ones,
+            write_mask,
+            mapped: true,
+            cgb_only: false,
+        }
+    }
+
+    const fn cgb(read_ones: u8, write_mask: u8) -> Self {
+        IoRegister {
+            cgb_only: true,
+            ..IoRegister::new(read_ones, write_mask)
+        }
+    }
+}
+
+const UNMAPPED: IoRegister = IoRegister {
+    read_ones: 0xff,
+    write_mask: 0x00,
+    mapped: false,
+    cgb_only: false,
+};
+
+const REGISTERS: [IoRegister; 0x80] = build_registers();
+
+pub fn register(addr: u16) -> &'static IoRegister {
+    &REGISTERS[(addr - 0xff00) as usize]
+}
+
+const fn build_registers() -> [IoRegister; 0x80] {
+    let mut r = [UNMAPPED; 0x80];
+
+    r[0x00] = IoRegister::new(0xc0, 0x30); // P1
+    r[0x01] = IoRegister::new(0x00, 0xff); // SB
+    r[0x02] = IoRegister::new(0x7e, 0x81); // SC
+    r[0x04] = IoRegister::new(0x00, 0xff); // DIV
+    r[0x05] = IoRegister::new(0x00, 0xff); // TIMA
+    r[0x06] = IoRegister::new(0x00, 0xff); // TMA
+    r[0x07] = IoRegister::new(0xf8, 0x07); // TAC
+    r[0x0f] = IoRegister::new(0xe0, 0x1f); // IF
+
+    r[0x10] = IoRegister::new(0x80, 0x7f); // NR10
+    r[0x11] = IoRegister::new(0x3f, 0xff); // NR11
+    r[0x12] = IoRegister::new(0x00, 0xff); // NR12
+    r[0x13] = IoRegister::new(0xff, 0xff); // NR13
+    r[0x14] = IoRegister::new(0xbf, 0xc7); // NR14
+    r[0x16] = IoRegister::new(0x3f, 0xff); // NR21
+    r[0x17] = IoRegister::new(0x00, 0xff); // NR22
+    r[0x18] = IoRegister::new(0xff, 0xff); // NR23
+    r[0x19] = IoRegister::new(0xbf, 0xc7); // NR24
+    r[0x1a] = IoRegister::new(0x7f, 0x80); // NR30
+    r[0x1b] = IoRegister::new(0xff, 0xff); // NR31
+    r[0x1c] = IoRegister::new(0x9f, 0x60); // NR32
+    r[0x1d] = IoRegister::new(0xff, 0xff); // NR33
+    r[0x1e] = IoRegister::new(0xbf, 0xc7); // NR34
+    r[0x20] = IoRegister::new(0xff, 0x3f); // NR41
+    r[0x21] = IoRegister::new(0x00, 0xff); // NR42
+    r[0x22] = IoRegister::new(0x00, 0xff); // NR43
+    r[0x23] = IoRegister::new(0xbf, 0xc0); // NR44
+    r[0x24] = IoRegister::new(0x00, 0xff); // NR50
+    r[0x25] = IoRegister::new(0x00, 0xff); // NR51
+    r[0x26] = IoRegister::new(0x70, 0x80); // NR52
+    let mut i = 0x30;
+    while i < 0x40 {
+        r[i] = IoRegister::new(0x00, 0xff); // WAVE
+        i += 1;
+    }
+
+    r[0x40] = IoRegister::new(0x00, 0xff); // LCDC
+    r[0x41] = IoRegister::new(0x80, 0x78); // STAT
+    r[0x42] = IoRegister::new(0x00, 0xff); // SCY
+    r[0x43] = IoRegister::new(0x00, 0xff); // SCX
+    r[0x44] = IoRegister::new(0x00, 0x00); // LY
+    r[0x45] = IoRegister::new(0x00, 0xff); // LYC
+    r[0x46] = IoRegister::new(0x00, 0xff); // DMA
+    r[0x47] = IoRegister::new(0x00, 0xff); // BGP
+    r[0x48] = IoRegister::new(0x00, 0xff); // OBP0
+    r[0x49] = IoRegister::new(0x00, 0xff); // OBP1
+    r[0x4a] = IoRegister::new(0x00, 0xff); // WY
+    r[0x4b] = IoRegister::new(0x00, 0xff); // WX
+    r[0x50] = IoRegister::new(0xff, 0x01); // BANK, boot ROM disable
+
+    r[0x4d] = IoRegister::cgb(0x7e, 0x01); // KEY1
+    r[0x4f] = IoRegister::cgb(0xfe, 0x01); // VBK
+    r[0x51] = IoRegister::cgb(0xff, 0xff); // HDMA1
+    r[0x52] = IoRegister::cgb(0xff, 0xf0); // HDMA2
+    r[0x53] = IoRegister::cgb(0xff, 0x1f); // HDMA3
+    r[0x54] = IoRegister::cgb(0xff, 0xf0); // HDMA4
+    r[0x55] = IoRegister::cgb(0x00, 0xff); // HDMA5
+    r[0x56] = IoRegister::cgb(0x3c, 0xc1); // RP
+    r[0x68] = IoRegister::cgb(0x40, 0xbf); // BCPS
+    r[0x69] = IoRegister::cgb(0x00, 0xff); // BCPD
+    r[0x6a] = IoRegister::cgb(0x40, 0xbf); // OCPS
+    r[0x6b] = IoRegister::cgb(0x00, 0xff); // OCPD
+    r[0x6c] = IoRegister::cgb(0xfe, 0x01); // OPRI
+    r[0x70] = IoRegister::cgb(0xf8, 0x07); // SVBK
+    r[0x72] = IoRegister::cgb(0x00, 0xff); // FF72
+    r[0x73] = IoRegister::cgb(0x00, 0xff); // FF73
+    r[0x74] = IoRegister::cgb(0x00, 0xff); // FF74
+    r[0x75] = IoRegister::cgb(0x8f, 0x70); // FF75
+    r[0x76] = IoRegister::cgb(0x00, 0x00); // PCM12
+    r[0x77] = IoRegister::cgb(0x00, 0x00); // PCM34
+
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unmapped_registers_read_ff() {
+        for addr in [0xff03, 0xff15, 0xff1f, 0xff27, 0xff4c, 0xff7f] {
+            let reg = register(addr);
+            assert!(!reg.mapped, "{addr:#x} should be unmapped");
+            assert_eq!(reg.read_ones, 0xff);
+            assert_eq!(reg.write_mask, 0x00);
+        }
+    }
+
+    #[test]
+    fn readable_bits_are_not_forced() {
+        // NR52 exposes the power bit and the 4 channel status bits
+        assert_eq!(!register(0xff26).read_ones, 0x8f);
+        // STAT bit 7 is always set
+        assert_eq!(register(0xff41).read_ones, 0x80);
+        assert_eq!(register(0xff0f).read_ones, 0xe0);
+    }
+}
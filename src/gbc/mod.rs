@@ -1,40 +1,299 @@
+pub mod apu;
+pub mod archive;
 pub mod bus;
 pub mod cartridge;
 pub mod cpu;
+pub mod gbs;
 pub mod sound;
 pub mod input;
+pub mod io;
+pub mod model;
+pub mod palette;
 pub mod ppu;
+pub mod rtc;
+pub mod save;
+pub mod sgb;
 pub mod timer;
 
+mod blip;
+mod camera;
+mod eeprom;
 mod memory;
+mod recorder;
+mod vgm;
+#[cfg(test)]
+mod test_dir;
 
-use anyhow::Result;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
+use anyhow::{bail, Context, Result};
+
+use apu::FilterModel;
 use bus::Bus;
 
 use cartridge::{load_rom};
 use cpu::Cpu;
+use model::Model;
 use ppu::{PpuInterrupt};
+use rtc::{ClockSource, RtcTime};
 
 use crate::gui::{self, Message};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmuError {
+    // The CPU fetched one of the 11 unused opcodes and locked up
+    IllegalOpcode { pc: u16, opcode: u8 },
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmuError::IllegalOpcode { pc, opcode } => {
+                write!(f, "CPU locked up on illegal opcode {opcode:#04x} at {pc:#06x}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmuError {}
+
+#[derive(Default)]
+pub struct Config {
+    // Boot ROM to run before the cartridge, post-boot state is emulated otherwise
+    pub boot_rom: Option<String>,
+    // Console to emulate, detected from the cartridge header if not set
+    pub model: Option<Model>,
+    // One of palette::MANUAL, the console default is used if not set
+    pub palette: Option<usize>,
+    pub clock: ClockSource,
+    // Cartridge clock value to set at startup, kept in the save
+    pub set_rtc: Option<RtcTime>,
+    // Where .sav files go, next to the ROM if not set
+    pub save_dir: Option<PathBuf>,
+    // ROM to load from a zip archive, the first one if not set
+    pub zip_entry: Option<String>,
+    // MBC1 multicart wiring, detected from the ROM if not set
+    pub multicart: Option<bool>,
+    // No window and no audio device
+    pub headless: bool,
+    // WAV file recorded from startup, the recording hotkey also uses it
+    pub record_audio: Option<PathBuf>,
+    // One more WAV file per channel when recording
+    pub record_stems: bool,
+    // VGM log of the sound registers from startup, the hotkey also uses it
+    pub record_vgm: Option<PathBuf>,
+    // High-pass filter of the sound output, the one of the console if not set
+    pub audio_filter: Option<FilterModel>,
+    // Output device name or part of it, the system default if not set
+    pub audio_device: Option<String>,
+    // In milliseconds, sound::DEFAULT_LATENCY_MS if not set
+    pub audio_latency: Option<u32>,
+}
+
+// The first free name among song.wav, song-2.wav...
+fn free_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    (1..)
+        .map(|i| match i {
+            1 => path.to_owned(),
+            _ => path.with_file_name(format!("{stem}-{i}.{extension}")),
+        })
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+// Battery RAM is flushed every 5 seconds if it changed
+const SAVE_INTERVAL: u32 = 300;
+
 pub struct Emu {
     cpu: Cpu,
     bus: Bus,
+    palette: Option<usize>,
+    frames: u32,
+    record_path: PathBuf,
+    record_stems: bool,
+    vgm_path: PathBuf,
 }
 
 impl Emu {
-    pub fn new(rom_name: &str) -> Result<Self> {
-        let rom = load_rom(rom_name)?;
-        let bus = Bus::new(rom)?;
-        let mut cpu = Cpu::new();
+    pub fn new(rom_name: &str, config: &Config) -> Result<Self> {
+        let (rom, header) = load_rom(rom_name, config)?;
+        let model = config.model.unwrap_or_else(|| Model::detect(header.gbc));
+        println!("Hardware model: {model:?}");
+        let mut bus = Bus::new(rom, model, config)?;
+        let mut cpu = Cpu::new(model);
 
         cpu.reset();
+        match &config.boot_rom {
+            Some(path) => {
+                let data = std::fs::read(path).with_context(|| format!("Cannot read boot ROM {path}"))?;
+                bus.load_boot_rom(data)?;
+            }
+            None => {
+                cpu.skip_boot(header.gbc);
+                bus.skip_boot(header.gbc);
+            }
+        }
+
+        if model == Model::Sgb && header.sgb {
+            bus.enable_sgb();
+        }
+
+        let dmg_palette = match config.palette {
+            Some(index) => palette::manual(index),
+            None if model.is_cgb() && !header.gbc => {
+                palette::from_header(&header.title_bytes, header.nintendo)
+            }
+            None => palette::grayscale(),
+        };
+        bus.ppu.set_dmg_palette(dmg_palette);
+
+        let mut emu = Self {
+            cpu,
+            bus,
+            palette: config.palette,
+            frames: 0,
+            record_path: config
+                .record_audio
+                .clone()
+                .unwrap_or_else(|| Path::new(rom_name).with_extension("wav")),
+            record_stems: config.record_stems,
+            vgm_path: config
+                .record_vgm
+                .clone()
+                .unwrap_or_else(|| Path::new(rom_name).with_extension("vgm")),
+        };
+        if let Some(time) = config.set_rtc {
+            emu.set_rtc(time)?;
+        }
+        if let Some(path) = &config.record_audio {
+            emu.bus.sound.start_recording(path, config.record_stems)?;
+        }
+        if let Some(path) = &config.record_vgm {
+            emu.bus.sound.start_vgm(path)?;
+        }
+        Ok(emu)
+    }
+
+    // Also done when the emulator is dropped
+    pub fn save(&mut self) {
+        if let Err(e) = self.bus.cartridge.save() {
+            eprintln!("Game save failed: {e:#}");
+        }
+    }
+
+    pub fn set_rtc(&mut self, time: RtcTime) -> Result<()> {
+        match self.bus.cartridge.rtc() {
+            Some(rtc) => {
+                rtc.set(time);
+                Ok(())
+            }
+            None => bail!("The cartridge has no clock"),
+        }
+    }
+
+    // Fill level of the audio queue, see sound::TARGET_FILL
+    pub fn audio_fill(&self) -> f32 {
+        self.bus.sound.fill()
+    }
 
-        Ok(Self { cpu, bus })
+    // Channels are numbered 0 to 3, like NR51 bits. The methods taking a
+    // channel panic when it is not in 0..4.
+    pub fn toggle_channel_mute(&mut self, channel: usize) {
+        let mixer = self.bus.sound.mixer();
+        mixer.muted[channel] = !mixer.muted[channel];
+        println!("Channel {} {}", channel + 1, if mixer.muted[channel] { "muted" } else { "unmuted" });
     }
 
-    pub fn get_next_frame(&mut self, events: &[Message], rendering_texture: &mut [u8; gui::SIZE]) {
+    pub fn toggle_channel_solo(&mut self, channel: usize) {
+        let mixer = self.bus.sound.mixer();
+        mixer.solo[channel] = !mixer.solo[channel];
+        println!("Channel {} solo {}", channel + 1, if mixer.solo[channel] { "on" } else { "off" });
+    }
+
+    pub fn channel_gain(&self, channel: usize) -> f32 {
+        self.bus.sound.mixer_settings().gain[channel]
+    }
+
+    pub fn set_channel_gain(&mut self, channel: usize, gain: f32) {
+        let gain = gain.max(0.0);
+        self.bus.sound.mixer().gain[channel] = gain;
+        println!("Channel {} gain: {:.0}%", channel + 1, gain * 100.0);
+    }
+
+    pub fn master_volume(&self) -> f32 {
+        self.bus.sound.mixer_settings().master
+    }
+
+    // From 0 to 1
+    pub fn set_master_volume(&mut self, volume: f32) {
+        let volume = volume.clamp(0.0, 1.0);
+        self.bus.sound.mixer().master = volume;
+        println!("Volume: {:.0}%", volume * 100.0);
+    }
+
+    pub fn toggle_mute(&mut self) {
+        let mixer = self.bus.sound.mixer();
+        mixer.mute = !mixer.mute;
+        println!("Sound {}", if mixer.mute { "muted" } else { "unmuted" });
+    }
+
+    pub fn toggle_recording(&mut self) {
+        if self.bus.sound.is_recording() {
+            self.bus.sound.stop_recording();
+            return;
+        }
+        let path = free_path(&self.record_path);
+        if let Err(e) = self.bus.sound.start_recording(&path, self.record_stems) {
+            eprintln!("Audio recording failed: {e:#}");
+        }
+    }
+
+    pub fn toggle_vgm(&mut self) {
+        if self.bus.sound.is_logging_vgm() {
+            self.bus.sound.stop_vgm();
+            return;
+        }
+        let path = free_path(&self.vgm_path);
+        if let Err(e) = self.bus.sound.start_vgm(&path) {
+            eprintln!("VGM logging failed: {e:#}");
+        }
+    }
+
+    // Next or previous track of GBS files, ignored by cartridges
+    pub fn select_track(&mut self, delta: i32) {
+        if let Some(tracks) = self.bus.cartridge.tracks() {
+            tracks.select(delta);
+            println!("Track {}/{}", tracks.current + 1, tracks.count);
+        }
+    }
+
+    // Accelerometer of MBC7 cartridges, ignored by the others
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.bus.cartridge.set_tilt(x, y);
+    }
+
+    // Switch to the next of the 12 boot ROM palettes
+    pub fn cycle_palette(&mut self) {
+        let index = self.palette.map_or(0, |i| (i + 1) % palette::MANUAL.len());
+        println!("Palette: {}", palette::MANUAL[index].0);
+        self.bus.ppu.set_dmg_palette(palette::manual(index));
+        self.palette = Some(index);
+    }
+
+    // Size of the frames, with the border in SGB mode
+    pub fn screen_size(&self) -> (usize, usize) {
+        match self.bus.sgb {
+            Some(_) => (gui::SGB_WIDTH, gui::SGB_HEIGHT),
+            None => (gui::WIDTH, gui::HEIGHT),
+        }
+    }
+
+    // The frame is always rendered, even if an error is returned: a locked up
+    // console keeps displaying. The texture has the size given by screen_size.
+    pub fn get_next_frame(&mut self, events: &[Message], rendering_texture: &mut [u8]) -> Result<(), EmuError> {
         let mut frame_done = false;
         loop {
             self.cpu.tick(&mut self.bus);
@@ -50,9 +309,12 @@ impl Emu {
                 }
             }
 
+            self.bus.cartridge.tick();
+
             if self.bus.timer.tick() {
                 self.bus.requested_interrupts |= bus::TIMER;
             }
+            self.bus.sound.tick(self.bus.timer.get_div());
             for ev in events {
                 if self.bus.joypad.update(ev) {
                     self.bus.requested_interrupts |= bus::JOYPAD
@@ -60,9 +322,26 @@ impl Emu {
             }
 
             if frame_done {
-                self.bus.ppu.render(rendering_texture);
+                match &mut self.bus.sgb {
+                    Some(sgb) => {
+                        sgb.vblank(&self.bus.ppu);
+                        sgb.render(rendering_texture);
+                    }
+                    None => {
+                        let target = rendering_texture.try_into().expect("Frame buffer size");
+                        self.bus.ppu.render(target);
+                    }
+                }
+                self.frames = self.frames.wrapping_add(1);
+                if self.frames.is_multiple_of(SAVE_INTERVAL) {
+                    self.save();
+                }
                 break;
             }
         }
+        match self.cpu.take_error() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
     }
 }
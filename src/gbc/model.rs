@@ -0,0 +1,43 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Error};
+
+// Emulated console. Games tell them apart through the value of A after boot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Model {
+    Dmg,
+    Mgb, // Game Boy Pocket
+    Sgb,
+    Cgb,
+    Agb, // Game Boy Advance in GBC mode
+}
+
+impl Model {
+    // Pick the console from the CGB flag at 0x143
+    pub fn detect(gbc_cartridge: bool) -> Self {
+        if gbc_cartridge {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    pub fn is_cgb(self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+}
+
+impl FromStr for Model {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "dmg" => Model::Dmg,
+            "mgb" => Model::Mgb,
+            "sgb" => Model::Sgb,
+            "cgb" => Model::Cgb,
+            "agb" => Model::Agb,
+            _ => bail!("Unknown model {s}, expected one of dmg, mgb, sgb, cgb, agb"),
+        })
+    }
+}
@@ -0,0 +1,313 @@
+// Colors used to display DMG games. On a Game Boy Color the boot ROM picks a
+// colorization from a checksum of the title, or from a button combo held
+// while the logo is displayed.
+
+pub type Rgb = (u8, u8, u8);
+
+#[derive(Clone, Copy)]
+pub struct DmgPalette {
+    pub bg: [Rgb; 4],
+    pub obj0: [Rgb; 4],
+    pub obj1: [Rgb; 4],
+}
+
+const GRAY: [Rgb; 4] = [(150, 150, 150), (100, 100, 100), (50, 50, 50), (0, 0, 0)];
+
+pub fn grayscale() -> DmgPalette {
+    DmgPalette {
+        bg: GRAY,
+        obj0: GRAY,
+        obj1: GRAY,
+    }
+}
+
+// Boot ROM palettes, 4 RGB555 colors each
+const COLORS: [u16; 120] = [
+    0x7fff, 0x32bf, 0x00d0, 0x0000, // 0
+    0x639f, 0x4279, 0x15b0, 0x04cb,
+    0x7fff, 0x6e31, 0x454a, 0x0000,
+    0x7fff, 0x1bef, 0x0200, 0x0000,
+    0x7fff, 0x421f, 0x1cf2, 0x0000,
+    0x7fff, 0x5294, 0x294a, 0x0000, // 5
+    0x7fff, 0x03ff, 0x012f, 0x0000,
+    0x7fff, 0x03ef, 0x01d6, 0x0000,
+    0x7fff, 0x42b5, 0x3dc8, 0x0000,
+    0x7e74, 0x03ff, 0x0180, 0x0000,
+    0x67ff, 0x77ac, 0x1a13, 0x2d6b, // 10
+    0x7ed6, 0x4bff, 0x2175, 0x0000,
+    0x53ff, 0x4a5f, 0x7e52, 0x0000,
+    0x4fff, 0x7ed2, 0x3a4c, 0x1ce0,
+    0x03ed, 0x7fff, 0x255f, 0x0000,
+    0x036a, 0x021f, 0x03ff, 0x7fff, // 15
+    0x7fff, 0x01df, 0x0112, 0x0000,
+    0x231f, 0x035f, 0x00f2, 0x0009,
+    0x7fff, 0x03ea, 0x011f, 0x0000,
+    0x299f, 0x001a, 0x000c, 0x0000,
+    0x7fff, 0x027f, 0x001f, 0x0000, // 20
+    0x7fff, 0x03e0, 0x0206, 0x0120,
+    0x7fff, 0x7eeb, 0x001f, 0x7c00,
+    0x7fff, 0x3fff, 0x7e00, 0x001f,
+    0x7fff, 0x03ff, 0x001f, 0x0000,
+    0x03ff, 0x001f, 0x000c, 0x0000, // 25
+    0x7fff, 0x033f, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037f, 0x7fff,
+    0x7fff, 0x7e8c, 0x7c00, 0x0000,
+    0x7fff, 0x1bef, 0x6180, 0x0000,
+];
+
+const fn comb(obj0: usize, obj1: usize, bg: usize) -> (usize, usize, usize) {
+    (obj0 * 4, obj1 * 4, bg * 4)
+}
+
+// (OBJ0, OBJ1, BG) offsets in COLORS. A few combinations start in the
+// middle of a palette, like the boot ROM does.
+const COMBINATIONS: [(usize, usize, usize); 51] = [
+    comb(4, 4, 29), // 0
+    comb(18, 18, 18),
+    comb(20, 20, 20),
+    comb(24, 24, 24),
+    comb(9, 9, 9),
+    comb(0, 0, 0), // 5
+    comb(27, 27, 27),
+    comb(5, 5, 5),
+    comb(12, 12, 12),
+    comb(26, 26, 26),
+    comb(16, 8, 8), // 10
+    comb(4, 28, 28),
+    comb(4, 2, 2),
+    comb(3, 4, 4),
+    comb(4, 29, 29),
+    comb(28, 4, 28), // 15
+    comb(2, 17, 2),
+    comb(16, 16, 8),
+    comb(4, 4, 7),
+    comb(4, 4, 18),
+    comb(4, 4, 20), // 20
+    comb(19, 19, 9),
+    (4 * 4 - 1, 4 * 4 - 1, 11 * 4),
+    comb(17, 17, 2),
+    comb(4, 4, 2),
+    comb(4, 4, 3), // 25
+    comb(28, 28, 0),
+    comb(3, 3, 0),
+    comb(0, 0, 1),
+    comb(18, 22, 18),
+    comb(20, 22, 20), // 30
+    comb(24, 22, 24),
+    comb(16, 22, 8),
+    comb(17, 4, 13),
+    (28 * 4 - 1, 0, 14 * 4),
+    (28 * 4 - 1, 4 * 4, 15 * 4), // 35
+    comb(19, 22, 9),
+    comb(16, 28, 10),
+    comb(4, 23, 28),
+    comb(17, 22, 2),
+    comb(4, 0, 2), // 40
+    comb(4, 28, 3),
+    comb(28, 3, 0),
+    comb(3, 28, 4),
+    comb(21, 28, 4),
+    comb(3, 28, 0), // 45
+    comb(25, 3, 28),
+    comb(0, 28, 8),
+    comb(4, 3, 28),
+    comb(28, 3, 6),
+    comb(4, 28, 29), // 50
+];
+
+// Title checksum, 4th letter of the title when the checksum is ambiguous,
+// and combination. The first match wins.
+const TITLES: [(u8, Option<u8>, usize); 94] = [
+    (0x00, None, 0),
+    (0x88, None, 4),  // ALLEY WAY
+    (0x16, None, 5),  // YAKUMAN
+    (0x36, None, 35), // BASEBALL
+    (0xd1, None, 34), // TENNIS
+    (0xdb, None, 3),  // TETRIS
+    (0xf2, None, 31), // QIX
+    (0x3c, None, 15), // DR.MARIO
+    (0x8c, None, 10), // RADARMISSION
+    (0x92, None, 5),  // F1RACE
+    (0x3d, None, 19), // YOSSY NO TAMAGO
+    (0x5c, None, 36),
+    (0x58, None, 7),  // X
+    (0xc9, None, 37), // MARIOLAND2
+    (0x3e, None, 30), // YOSSY NO COOKIE
+    (0x70, None, 44), // ZELDA
+    (0x1d, None, 21),
+    (0x59, None, 32),
+    (0x69, None, 31), // TETRIS FLASH
+    (0x19, None, 20), // DONKEY KONG
+    (0x35, None, 5),  // MARIO'S PICROSS
+    (0xa8, None, 33),
+    (0x14, None, 13), // POKEMON RED
+    (0xaa, None, 14), // POKEMON GREEN
+    (0x75, None, 5),  // PICROSS 2
+    (0x95, None, 29), // YOSSY NO PANEPON
+    (0x99, None, 5),  // KIRAKIRA KIDS
+    (0x34, None, 18), // GAMEBOY GALLERY
+    (0x6f, None, 9),  // POCKETCAMERA
+    (0x15, None, 3),
+    (0xff, None, 2),  // BALLOON KID
+    (0x97, None, 26), // KINGOFTHEZOO
+    (0x4b, None, 25), // DMG FOOTBALL
+    (0x90, None, 25), // WORLD CUP
+    (0x17, None, 41), // OTHELLO
+    (0x10, None, 42), // SUPER RC PRO-AM
+    (0x39, None, 26), // DYNABLASTER
+    (0xf7, None, 45), // BOY AND BLOB GB2
+    (0xf6, None, 42), // MEGAMAN
+    (0xa2, None, 45), // STAR WARS-NOA
+    (0x49, None, 36),
+    (0x4e, None, 38), // WAVERACE
+    (0xc3, None, 26),
+    (0x68, None, 42), // LOLO2
+    (0xe0, None, 30), // YOSHI'S COOKIE
+    (0x8b, None, 41), // MYSTIC QUEST
+    (0xf0, None, 34),
+    (0xce, None, 34), // TOPRANKINGTENNIS
+    (0x0c, None, 5),  // MANSELL
+    (0x29, None, 42), // MEGAMAN3
+    (0xe8, None, 6),  // SPACE INVADERS
+    (0xb7, None, 5),  // GAME&WATCH
+    (0x86, None, 33), // DONKEYKONGLAND95
+    (0x9a, None, 25), // ASTEROIDS/MISCMD
+    (0x52, None, 42), // STREET FIGHTER 2
+    (0x01, None, 42), // DEFENDER/JOUST
+    (0x9d, None, 40), // KILLERINSTINCT95
+    (0x71, None, 2),  // TETRIS BLAST
+    (0x9c, None, 16), // PINOCCHIO
+    (0xbd, None, 25),
+    (0x5d, None, 42), // BA.TOSHINDEN
+    (0x6d, None, 42), // NETTOU KOF 95
+    (0x67, None, 5),
+    (0x3f, None, 0),  // TETRIS PLUS
+    (0x6b, None, 39), // DONKEYKONGLAND 3
+    (0xb3, Some(b'B'), 36),
+    (0x46, Some(b'E'), 32), // SUPER MARIOLAND
+    (0x28, Some(b'F'), 25), // GOLF
+    (0xa5, Some(b'A'), 6),  // SOLARSTRIKER
+    (0xc6, Some(b'A'), 32), // GBWARS
+    (0xd3, Some(b'R'), 12), // KAERUNOTAMENI
+    (0x27, Some(b'B'), 36),
+    (0x61, Some(b'E'), 11), // POKEMON BLUE
+    (0x18, Some(b'K'), 39), // DONKEYKONGLAND
+    (0x66, Some(b'E'), 18), // GAMEBOY GALLERY2
+    (0x6a, Some(b'K'), 39), // DONKEYKONGLAND 2
+    (0xbf, Some(b' '), 24), // KID ICARUS
+    (0x0d, Some(b'R'), 31), // TETRIS2
+    (0xf4, Some(b'-'), 50),
+    (0xb3, Some(b'U'), 17), // MOGURANYA
+    (0x46, Some(b'R'), 46),
+    (0x28, Some(b'A'), 6),  // GALAGA&GALAXIAN
+    (0xa5, Some(b'R'), 27), // BT2RAGNAROKWORLD
+    (0xc6, Some(b' '), 0),  // KEN GRIFFEY JR
+    (0xd3, Some(b'I'), 47),
+    (0x27, Some(b'N'), 41), // MAGNETIC SOCCER
+    (0x61, Some(b'A'), 41), // VEGAS STAKES
+    (0x18, Some(b'I'), 0),
+    (0x66, Some(b'L'), 0),  // MILLI/CENTI/PEDE
+    (0x6a, Some(b'I'), 34), // MARIO & YOSHI
+    (0xbf, Some(b'C'), 23), // SOCCER
+    (0x0d, Some(b'E'), 18), // POKEBOM
+    (0xf4, Some(b' '), 29), // G&W GALLERY
+    (0xb3, Some(b'R'), 28), // TETRIS ATTACK
+];
+
+// Button combos accepted by the boot ROM, and their combination
+pub const MANUAL: [(&str, usize); 12] = [
+    ("up", 5),
+    ("up-a", 43),
+    ("up-b", 28),
+    ("left", 48),
+    ("left-a", 40),
+    ("left-b", 7),
+    ("down", 8),
+    ("down-a", 3),
+    ("down-b", 49),
+    ("right", 1),
+    ("right-a", 0),
+    ("right-b", 6),
+];
+
+// 5 bits per channel, red in the low bits
+pub fn rgb555(c: u16) -> Rgb {
+    let expand = |x: u16| ((x & 0x1f) << 3 | (x & 0x1f) >> 2) as u8;
+    (expand(c), expand(c >> 5), expand(c >> 10))
+}
+
+fn rgb(offset: usize) -> [Rgb; 4] {
+    let mut res = [(0, 0, 0); 4];
+    for (i, color) in res.iter_mut().enumerate() {
+        *color = rgb555(COLORS[offset + i]);
+    }
+    res
+}
+
+fn combination(index: usize) -> DmgPalette {
+    let (obj0, obj1, bg) = COMBINATIONS[index];
+    DmgPalette {
+        bg: rgb(bg),
+        obj0: rgb(obj0),
+        obj1: rgb(obj1),
+    }
+}
+
+pub fn manual(index: usize) -> DmgPalette {
+    combination(MANUAL[index].1)
+}
+
+pub fn find_manual(name: &str) -> Option<usize> {
+    MANUAL.iter().position(|(n, _)| *n == name)
+}
+
+// Colorization chosen by the CGB boot ROM for a DMG cartridge. Only games
+// published by Nintendo are looked up.
+pub fn from_header(title: &[u8; 16], nintendo: bool) -> DmgPalette {
+    if !nintendo {
+        return combination(0);
+    }
+    let checksum = title.iter().fold(0u8, |acc, x| acc.wrapping_add(*x));
+    let fourth_letter = title[3];
+    let index = TITLES
+        .iter()
+        .find(|(sum, letter, _)| *sum == checksum && letter.is_none_or(|l| l == fourth_letter))
+        .map_or(0, |(_, _, index)| *index);
+    combination(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn title(name: &str) -> [u8; 16] {
+        let mut res = [0; 16];
+        res[..name.len()].copy_from_slice(name.as_bytes());
+        res
+    }
+
+    #[test]
+    fn pokemon_red_and_blue() {
+        let red = from_header(&title("POKEMON RED"), true);
+        assert_eq!(red.bg[1], (0xff, 0x84, 0x84));
+        assert_eq!(red.obj0[1], (0x7b, 0xff, 0x31));
+        let blue = from_header(&title("POKEMON BLUE"), true);
+        assert_eq!(blue.bg[1], (0x63, 0xa5, 0xff));
+        assert_eq!(blue.obj0[1], (0xff, 0x84, 0x84));
+    }
+
+    #[test]
+    fn fourth_letter_disambiguation() {
+        // Same checksum as POKEMON BLUE
+        let vegas = from_header(&title("VEGAS STAKES"), true);
+        assert_eq!(vegas.bg[1], (0x7b, 0xff, 0x31));
+    }
+
+    #[test]
+    fn unknown_and_third_party_use_default() {
+        let default = combination(0);
+        assert_eq!(from_header(&title("POKEMON RED"), false).bg, default.bg);
+        assert_eq!(from_header(&title("PLATFORMER"), true).bg, default.bg);
+        assert_eq!(manual(find_manual("right-a").unwrap()).bg, default.bg);
+    }
+}
@@ -1,10 +1,16 @@
 use super::bus::Busable;
+use super::model::Model;
+use super::palette::{self, DmgPalette, Rgb};
 use arrayvec::ArrayVec;
 use num_enum::IntoPrimitive;
 
 use crate::gui;
 
 pub struct Ppu {
+    dmg_palette: DmgPalette,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
     background_palette: ArrayVec<Color, 4>,
     obj_palette0: ArrayVec<Color, 4>,
     obj_palette1: ArrayVec<Color, 4>,
@@ -34,6 +40,7 @@ pub struct Ppu {
     oam: [u8; 0xA0],
 
     wait: usize,
+    obj_priority_by_x: bool, // OPRI, CGB sorts sprites by OAM index
 
     texture: Vec<[Color; gui::WIDTH]>,
 }
@@ -45,17 +52,16 @@ pub enum PpuInterrupt {
 }
 
 impl Ppu {
-    pub fn new() -> Self {
+    pub fn new(model: Model) -> Self {
+        let dmg_palette = palette::grayscale();
         Ppu {
-            background_palette: (0..4)
-                .map(|idx| bw_palette(idx as u8, idx, PaletteType::Background))
-                .collect::<ArrayVec<Color, 4>>(),
-            obj_palette0: (0..4)
-                .map(|idx| bw_palette(idx as u8, idx, PaletteType::Sprite))
-                .collect::<ArrayVec<Color, 4>>(),
-            obj_palette1: (0..4)
-                .map(|idx| bw_palette(idx as u8, idx, PaletteType::Sprite))
-                .collect::<ArrayVec<Color, 4>>(),
+            dmg_palette,
+            bgp: 0xe4,
+            obp0: 0xe4,
+            obp1: 0xe4,
+            background_palette: shades(&dmg_palette.bg, 0xe4, PaletteType::Background),
+            obj_palette0: shades(&dmg_palette.obj0, 0xe4, PaletteType::Sprite),
+            obj_palette1: shades(&dmg_palette.obj1, 0xe4, PaletteType::Sprite),
             scx: 0,
             scy: 0,
             wx: 0,
@@ -82,60 +88,46 @@ impl Ppu {
             oam: [0; 0xA0],
 
             wait: 0,
+            obj_priority_by_x: !model.is_cgb(),
 
             texture: vec![[Color::new(0, 0, 0, PaletteType::Background); gui::WIDTH]; gui::HEIGHT],
         }
     }
 
+    // Colors of the 4 shades, the BGP/OBP registers are kept
+    pub fn set_dmg_palette(&mut self, dmg_palette: DmgPalette) {
+        self.dmg_palette = dmg_palette;
+        self.set_bgp(self.bgp);
+        self.set_obp0(self.obp0);
+        self.set_obp1(self.obp1);
+    }
+
     pub fn get_bgp(&self) -> u8 {
-        self.background_palette
-            .iter()
-            .enumerate()
-            .fold(0, |acc, (i, color)| (acc | color.palette_index << (2 * i)))
+        self.bgp
     }
 
     pub fn set_bgp(&mut self, new_palette: u8) {
-        for (i, col) in self.background_palette.iter_mut().enumerate() {
-            *col = bw_palette(
-                (new_palette & (0x3 << (2 * i))) >> (2 * i),
-                i as u8,
-                PaletteType::Background,
-            );
-        }
+        self.bgp = new_palette;
+        self.background_palette =
+            shades(&self.dmg_palette.bg, new_palette, PaletteType::Background);
     }
 
     pub fn get_obp0(&self) -> u8 {
-        self.obj_palette0
-            .iter()
-            .enumerate()
-            .fold(0, |acc, (i, color)| (acc | color.palette_index << (2 * i)))
+        self.obp0
     }
 
     pub fn set_obp0(&mut self, new_palette: u8) {
-        for (i, col) in self.obj_palette0.iter_mut().enumerate() {
-            *col = bw_palette(
-                (new_palette & (0x3 << (2 * i))) >> (2 * i),
-                i as u8,
-                PaletteType::Sprite,
-            );
-        }
+        self.obp0 = new_palette;
+        self.obj_palette0 = shades(&self.dmg_palette.obj0, new_palette, PaletteType::Sprite);
     }
 
     pub fn get_obp1(&self) -> u8 {
-        self.obj_palette1
-            .iter()
-            .enumerate()
-            .fold(0, |acc, (i, color)| (acc | color.palette_index << (2 * i)))
+        self.obp1
     }
 
     pub fn set_obp1(&mut self, new_palette: u8) {
-        for (i, col) in self.obj_palette1.iter_mut().enumerate() {
-            *col = bw_palette(
-                (new_palette & (0x3 << (2 * i))) >> (2 * i),
-                i as u8,
-                PaletteType::Sprite,
-            );
-        }
+        self.obp1 = new_palette;
+        self.obj_palette1 = shades(&self.dmg_palette.obj1, new_palette, PaletteType::Sprite);
     }
 
     pub fn get_wx(&self) -> u8 {
@@ -174,10 +166,6 @@ impl Ppu {
         self.ly
     }
 
-    pub fn set_ly(&self, _val: u8) {
-        panic!("LY is not writable");
-    }
-
     pub fn get_lyc(&self) -> u8 {
         self.lyc
     }
@@ -261,6 +249,14 @@ impl Ppu {
         self.int_hblank = val & 0x08 != 0;
     }
 
+    pub fn get_opri(&self) -> u8 {
+        self.obj_priority_by_x as u8
+    }
+
+    pub fn set_opri(&mut self, val: u8) {
+        self.obj_priority_by_x = val & 1 != 0;
+    }
+
     pub fn tick(&mut self) -> PpuInterrupt {
         if !self.enabled {
             return PpuInterrupt::None;
@@ -393,7 +389,9 @@ impl Ppu {
     fn render_sprites(&mut self) {
         let mut oam_data = self.get_sprites_on_line();
 
-        oam_data.sort_by_key(|sprite| sprite.x as i16);
+        if self.obj_priority_by_x {
+            oam_data.sort_by_key(|sprite| sprite.x as i16);
+        }
         for sprite in oam_data.iter() {
             let tile = self.get_sprite_tile_line(sprite);
             let palette = if sprite.palette {
@@ -525,6 +523,25 @@ impl Ppu {
         res
     }
 
+    pub fn shade(&self, x: usize, y: usize) -> u8 {
+        self.texture[y][x].shade
+    }
+
+    // SGB VRAM transfers read the 256 tiles shown by the background map, 20
+    // per line, ignoring scrolling
+    pub fn screen_tile_data(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(0x1000);
+        for i in 0..256 {
+            let tile_index = self.vram[self.bg_map_select as usize + i / 20 * 32 + i % 20];
+            let addr = match self.win_bg_data {
+                WindowBGTileData::Low => (0x1000 + tile_index as i8 as isize * 16) as usize,
+                WindowBGTileData::High => tile_index as usize * 16,
+            };
+            res.extend_from_slice(&self.vram[addr..addr + 16]);
+        }
+        res
+    }
+
     pub fn render(&self, target: &mut [u8; gui::SIZE]) {
         let mut index = 0;
         for y in 0..gui::HEIGHT {
@@ -619,6 +636,7 @@ struct Color {
     g: u8,
     b: u8,
     palette_index: u8,
+    shade: u8, // after BGP/OBP, what the SGB colorizes
     palette_type: PaletteType,
 }
 
@@ -629,26 +647,28 @@ impl Color {
             g,
             b,
             palette_index: 0,
+            shade: 0,
             palette_type,
         }
     }
-    fn from_palette(r: u8, g: u8, b: u8, index: u8, palette_type: PaletteType) -> Self {
+    fn from_palette(r: u8, g: u8, b: u8, index: u8, shade: u8, palette_type: PaletteType) -> Self {
         Color {
             r,
             g,
             b,
             palette_index: index,
+            shade,
             palette_type,
         }
     }
 }
 
-fn bw_palette(entry: u8, index: u8, ptype: PaletteType) -> Color {
-    match entry {
-        3 => Color::from_palette(0, 0, 0, index, ptype),
-        2 => Color::from_palette(50, 50, 50, index, ptype),
-        1 => Color::from_palette(100, 100, 100, index, ptype),
-        0 => Color::from_palette(150, 150, 150, index, ptype),
-        x => panic!("Unknown BW color : {}", x),
-    }
+fn shades(colors: &[Rgb; 4], reg: u8, ptype: PaletteType) -> ArrayVec<Color, 4> {
+    (0..4)
+        .map(|i| {
+            let shade = reg >> (2 * i) & 0x3;
+            let (r, g, b) = colors[shade as usize];
+            Color::from_palette(r, g, b, i, shade, ptype)
+        })
+        .collect()
 }
@@ -0,0 +1,166 @@
+// Audio capture to 16-bit PCM WAV files: the stereo mix, and optionally one
+// stereo stem per APU channel next to it (song.wav, song-ch1.wav...).
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use super::apu::Frame;
+
+pub struct Recorder {
+    mix: WavWriter,
+    stems: Vec<WavWriter>,
+}
+
+impl Recorder {
+    pub fn new(path: &Path, sample_rate: u32, stems: bool) -> Result<Self> {
+        let mix = WavWriter::create(path, sample_rate)?;
+        let stems = if stems {
+            (1..=4)
+                .map(|channel| WavWriter::create(&stem_path(path, channel), sample_rate))
+                .collect::<Result<_>>()?
+        } else {
+            vec![]
+        };
+        println!("Recording audio to {}", path.display());
+        Ok(Recorder { mix, stems })
+    }
+
+    pub fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        self.mix.write(frame.mix())?;
+        for (stem, &channel) in self.stems.iter_mut().zip(frame.channels.iter()) {
+            stem.write(channel)?;
+        }
+        Ok(())
+    }
+
+    // Also done when dropped, but without reporting errors
+    pub fn finish(mut self) -> io::Result<()> {
+        self.mix.finish()?;
+        for stem in self.stems.iter_mut() {
+            stem.finish()?;
+        }
+        Ok(())
+    }
+}
+
+fn stem_path(path: &Path, channel: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{stem}-ch{channel}.wav"))
+}
+
+// The sizes in the header are written when the file is finished
+struct WavWriter {
+    file: BufWriter<File>,
+    samples: u32,
+    finished: bool,
+}
+
+const HEADER_SIZE: u32 = 44;
+
+impl WavWriter {
+    fn create(path: &Path, sample_rate: u32) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("Cannot create {}", path.display()))?;
+        let mut writer = WavWriter {
+            file: BufWriter::new(file),
+            samples: 0,
+            finished: false,
+        };
+        writer.write_header(sample_rate)?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self, sample_rate: u32) -> io::Result<()> {
+        let data_size = self.samples * 4;
+        let f = &mut self.file;
+        f.write_all(b"RIFF")?;
+        f.write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        f.write_all(b"WAVEfmt ")?;
+        f.write_all(&16u32.to_le_bytes())?;
+        f.write_all(&1u16.to_le_bytes())?; // PCM
+        f.write_all(&2u16.to_le_bytes())?; // stereo
+        f.write_all(&sample_rate.to_le_bytes())?;
+        f.write_all(&(sample_rate * 4).to_le_bytes())?;
+        f.write_all(&4u16.to_le_bytes())?; // bytes per frame
+        f.write_all(&16u16.to_le_bytes())?;
+        f.write_all(b"data")?;
+        f.write_all(&data_size.to_le_bytes())
+    }
+
+    fn write(&mut self, (left, right): (f32, f32)) -> io::Result<()> {
+        // The RIFF sizes are 32 bits, the recording stops before they overflow
+        let riff_size = (self.samples + 1)
+            .checked_mul(4)
+            .and_then(|size| size.checked_add(HEADER_SIZE - 8));
+        if riff_size.is_none() {
+            return Err(io::Error::other("the WAV file reached its 4 GiB limit"));
+        }
+        for sample in [left, right] {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.samples += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        let data_size = self.samples * 4;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&data_size.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_dir::TestDir;
+
+    #[test]
+    fn header_sizes_and_stems() {
+        let dir = TestDir::new("recorder");
+        let path = dir.join("song.wav");
+        let mut recorder = Recorder::new(&path, 48000, true).unwrap();
+        let mut frame = Frame::default();
+        frame.channels[0] = (0.5, 0.0);
+        frame.channels[2] = (0.25, -1.0);
+        recorder.write(&frame).unwrap();
+        recorder.write(&frame).unwrap();
+        recorder.finish().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(data[4..8], 44u32.to_le_bytes());
+        assert_eq!(data[24..28], 48000u32.to_le_bytes());
+        assert_eq!(data[40..44], 8u32.to_le_bytes());
+        assert_eq!(data[44..48], [0xff, 0x5f, 0x01, 0x80]); // 0.75 and -1
+        let stem = std::fs::read(dir.join("song-ch3.wav")).unwrap();
+        assert_eq!(stem[44..48], [0xff, 0x1f, 0x01, 0x80]);
+    }
+
+    #[test]
+    fn size_limit() {
+        let dir = TestDir::new("recorder-limit");
+        let mut writer = WavWriter::create(&dir.join("long.wav"), 48000).unwrap();
+        // One frame left before the RIFF size overflows
+        writer.samples = (u32::MAX - (HEADER_SIZE - 8)) / 4 - 1;
+        assert!(writer.write((0.0, 0.0)).is_ok());
+        assert!(writer.write((0.0, 0.0)).is_err());
+        writer.finish().unwrap();
+    }
+}
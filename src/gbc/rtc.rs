@@ -0,0 +1,367 @@
+// MBC3 real time clock. The registers are counters incremented once per
+// second, they can hold invalid values (seconds=60) which wrap at the
+// register width without carrying.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error};
+use time::OffsetDateTime;
+
+const CYCLES_PER_SECOND: u32 = 4_194_304;
+const SECONDS_PER_DAY: u64 = 24 * 3600;
+
+const SECONDS: usize = 0;
+const MINUTES: usize = 1;
+const HOURS: usize = 2;
+const DAYS_LOW: usize = 3;
+const DAYS_HIGH: usize = 4; // bit 0: day bit 8, bit 6: halt, bit 7: day carry
+
+const MASKS: [u8; 5] = [0x3f, 0x3f, 0x1f, 0xff, 0xc1];
+
+// Size of the footer appended to the save by VBA and BGB, older versions use a
+// 32 bits timestamp
+pub const FOOTER_SIZE: usize = 48;
+const SHORT_FOOTER_SIZE: usize = 44;
+const LEGACY_FOOTER_SIZE: usize = 9;
+
+// What makes the clock advance
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClockSource {
+    // Follows the host clock, also while the emulator is closed
+    #[default]
+    Host,
+    // Advances with emulated cycles only
+    Emulated,
+    // Emulated, starting from the same time at each run
+    Fixed(RtcTime),
+}
+
+impl FromStr for ClockSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "host" => ClockSource::Host,
+            "emulated" => ClockSource::Emulated,
+            _ => ClockSource::Fixed(s.parse()?),
+        })
+    }
+}
+
+// Value of the counters, written as days:hours:minutes:seconds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RtcTime {
+    pub days: u16,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+}
+
+impl FromStr for RtcTime {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s
+            .split(':')
+            .map(|x| x.parse::<u16>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Invalid clock time {s}"))?;
+        let [days, hours, minutes, seconds] = fields[..] else {
+            bail!("Invalid clock time {s}, expected days:hours:minutes:seconds");
+        };
+        if days > 0x1ff || hours > 23 || minutes > 59 || seconds > 59 {
+            bail!("Clock time {s} out of range, days go up to 511");
+        }
+        Ok(RtcTime {
+            days,
+            hours: hours as u8,
+            minutes: minutes as u8,
+            seconds: seconds as u8,
+        })
+    }
+}
+
+pub struct Rtc {
+    live: [u8; 5],
+    latched: [u8; 5],
+    cycles: u32,
+    latch_armed: bool,
+    source: ClockSource,
+    host_time: i64, // last synchronization with the host clock
+}
+
+fn host_now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+impl Rtc {
+    pub fn new(source: ClockSource) -> Self {
+        let mut rtc = Rtc {
+            live: [0; 5],
+            latched: [0; 5],
+            cycles: 0,
+            latch_armed: false,
+            source,
+            host_time: host_now(),
+        };
+        if let ClockSource::Fixed(time) = source {
+            rtc.set(time);
+        }
+        rtc
+    }
+
+    // Restore the clock from a save footer. With the host clock, the time
+    // spent since it was written is accounted for.
+    pub fn from_footer(footer: &[u8], source: ClockSource, now: i64) -> Option<Self> {
+        let word = |i: usize| u32::from_le_bytes(footer[i * 4..i * 4 + 4].try_into().unwrap());
+        let timestamp = match footer.len() {
+            FOOTER_SIZE => i64::from_le_bytes(footer[40..48].try_into().unwrap()),
+            SHORT_FOOTER_SIZE => word(10) as i64,
+            LEGACY_FOOTER_SIZE => return Some(Self::from_legacy_footer(footer, source, now)),
+            _ => return None,
+        };
+        let mut rtc = Rtc::new(source);
+        if let ClockSource::Fixed(_) = source {
+            return Some(rtc);
+        }
+        for (i, mask) in MASKS.iter().enumerate() {
+            rtc.live[i] = word(i) as u8 & mask;
+            rtc.latched[i] = word(i + 5) as u8 & mask;
+        }
+        if source == ClockSource::Host {
+            rtc.advance(now.saturating_sub(timestamp).max(0) as u64);
+        }
+        Some(rtc)
+    }
+
+    // Footer written by previous versions: 8 bytes of time then a halt flag.
+    // A halted clock stored the time since the epoch (big endian), a running
+    // one its offset from the host clock (little endian).
+    fn from_legacy_footer(footer: &[u8], source: ClockSource, now: i64) -> Self {
+        if let ClockSource::Fixed(_) = source {
+            return Rtc::new(source);
+        }
+        let halted = footer[8] != 0;
+        let total = if halted {
+            i64::from_be_bytes(footer[..8].try_into().unwrap())
+        } else {
+            now + i64::from_le_bytes(footer[..8].try_into().unwrap())
+        };
+        let mut rtc = Rtc::new(source);
+        rtc.advance(total.max(0) as u64);
+        if halted {
+            rtc.live[DAYS_HIGH] |= 0x40;
+        }
+        rtc.latched = rtc.live;
+        rtc
+    }
+
+    pub fn footer(&self, now: i64) -> [u8; FOOTER_SIZE] {
+        let mut res = [0; FOOTER_SIZE];
+        for i in 0..5 {
+            res[i * 4..i * 4 + 4].copy_from_slice(&(self.live[i] as u32).to_le_bytes());
+            res[20 + i * 4..24 + i * 4].copy_from_slice(&(self.latched[i] as u32).to_le_bytes());
+        }
+        res[40..].copy_from_slice(&now.to_le_bytes());
+        res
+    }
+
+    fn halted(&self) -> bool {
+        self.live[DAYS_HIGH] & 0x40 != 0
+    }
+
+    pub fn tick(&mut self) {
+        if self.halted() {
+            return;
+        }
+        self.cycles += 1;
+        if self.cycles == CYCLES_PER_SECOND {
+            self.cycles = 0;
+            match self.source {
+                ClockSource::Host => {
+                    // Emulation may run faster or slower than real time
+                    let now = host_now();
+                    self.advance(now.saturating_sub(self.host_time).max(0) as u64);
+                    self.host_time = now;
+                }
+                ClockSource::Emulated | ClockSource::Fixed(_) => self.increment_second(),
+            }
+        }
+    }
+
+    // Set the counters, the halt flag is kept and the carry cleared
+    pub fn set(&mut self, time: RtcTime) {
+        self.live[SECONDS] = time.seconds;
+        self.live[MINUTES] = time.minutes;
+        self.live[HOURS] = time.hours;
+        self.live[DAYS_HIGH] &= 0x40;
+        self.set_days(time.days as u64);
+        self.latched = self.live;
+        self.cycles = 0;
+        self.host_time = host_now();
+    }
+
+    // Registers 0x08 to 0x0c, reads return the latched values
+    pub fn read(&self, register: u8) -> u8 {
+        self.latched[(register - 0x08) as usize]
+    }
+
+    pub fn write(&mut self, register: u8, val: u8) {
+        let index = (register - 0x08) as usize;
+        if index == SECONDS {
+            self.cycles = 0;
+        }
+        self.live[index] = val & MASKS[index];
+        self.latched[index] = self.live[index];
+        // Time spent halted or before the write is not counted
+        self.host_time = host_now();
+    }
+
+    // Writing 0 then 1 copies the counters to the readable registers
+    pub fn write_latch(&mut self, val: u8) {
+        if self.latch_armed && val == 1 {
+            self.latched = self.live;
+        }
+        self.latch_armed = val == 0;
+    }
+
+    fn advance(&mut self, mut seconds: u64) {
+        if self.halted() {
+            return;
+        }
+        // Invalid values do not carry, step them until they wrap
+        while seconds > 0 && !self.is_valid() {
+            self.increment_second();
+            seconds -= 1;
+        }
+        let total = self.days() as u64 * SECONDS_PER_DAY
+            + self.live[HOURS] as u64 * 3600
+            + self.live[MINUTES] as u64 * 60
+            + self.live[SECONDS] as u64
+            + seconds;
+        let days = total / SECONDS_PER_DAY;
+        let time = total % SECONDS_PER_DAY;
+        self.live[SECONDS] = (time % 60) as u8;
+        self.live[MINUTES] = (time / 60 % 60) as u8;
+        self.live[HOURS] = (time / 3600) as u8;
+        self.set_days(days);
+    }
+
+    fn increment_second(&mut self) {
+        self.live[SECONDS] = (self.live[SECONDS] + 1) & MASKS[SECONDS];
+        if self.live[SECONDS] != 60 {
+            return;
+        }
+        self.live[SECONDS] = 0;
+        self.live[MINUTES] = (self.live[MINUTES] + 1) & MASKS[MINUTES];
+        if self.live[MINUTES] != 60 {
+            return;
+        }
+        self.live[MINUTES] = 0;
+        self.live[HOURS] = (self.live[HOURS] + 1) & MASKS[HOURS];
+        if self.live[HOURS] != 24 {
+            return;
+        }
+        self.live[HOURS] = 0;
+        self.set_days(self.days() as u64 + 1);
+    }
+
+    fn is_valid(&self) -> bool {
+        self.live[SECONDS] < 60 && self.live[MINUTES] < 60 && self.live[HOURS] < 24
+    }
+
+    fn days(&self) -> u16 {
+        self.live[DAYS_LOW] as u16 | (self.live[DAYS_HIGH] as u16 & 0x01) << 8
+    }
+
+    // The carry flag stays set until the game clears it
+    fn set_days(&mut self, days: u64) {
+        self.live[DAYS_LOW] = days as u8;
+        self.live[DAYS_HIGH] = (self.live[DAYS_HIGH] & 0xc0) | ((days >> 8) & 0x01) as u8;
+        if days > 0x1ff {
+            self.live[DAYS_HIGH] |= 0x80;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rtc() -> Rtc {
+        Rtc::new(ClockSource::Emulated)
+    }
+
+    fn run_seconds(rtc: &mut Rtc, seconds: u32) {
+        for _ in 0..seconds * CYCLES_PER_SECOND {
+            rtc.tick();
+        }
+    }
+
+    fn latch(rtc: &mut Rtc) {
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+    }
+
+    #[test]
+    fn invalid_values_wrap_without_carry() {
+        let mut rtc = rtc();
+        rtc.write(0x08, 63);
+        run_seconds(&mut rtc, 1);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 0);
+        assert_eq!(rtc.read(0x09), 0);
+
+        rtc.write(0x08, 59);
+        rtc.write(0x09, 59);
+        rtc.write(0x0a, 23);
+        rtc.write(0x0b, 0xff);
+        rtc.write(0x0c, 0x01);
+        run_seconds(&mut rtc, 1);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x0b), 0);
+        assert_eq!(rtc.read(0x0c), 0x80);
+    }
+
+    #[test]
+    fn halt_and_latch() {
+        let mut rtc = rtc();
+        rtc.write(0x0c, 0x40);
+        run_seconds(&mut rtc, 1);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 0);
+
+        rtc.write(0x0c, 0x00);
+        run_seconds(&mut rtc, 2);
+        assert_eq!(rtc.read(0x08), 0);
+        rtc.write_latch(1); // no 0 before
+        assert_eq!(rtc.read(0x08), 0);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 2);
+    }
+
+    #[test]
+    fn footer_round_trip_catches_up() {
+        let mut rtc = rtc();
+        rtc.write(0x0a, 23);
+        rtc.write(0x0b, 0xff);
+        let footer = rtc.footer(1000);
+        let loaded = Rtc::from_footer(&footer, ClockSource::Host, 1000 + 3600).unwrap();
+        assert_eq!(loaded.live, [0, 0, 0, 0, 1]);
+        assert!(Rtc::from_footer(&footer[..10], ClockSource::Host, 0).is_none());
+    }
+
+    #[test]
+    fn deterministic_sources_ignore_elapsed_time() {
+        let mut rtc = rtc();
+        rtc.write(0x0a, 23);
+        let footer = rtc.footer(1000);
+        let loaded = Rtc::from_footer(&footer, ClockSource::Emulated, 1000 + 3600).unwrap();
+        assert_eq!(loaded.live[HOURS], 23);
+
+        let time: RtcTime = "300:12:30:00".parse().unwrap();
+        let loaded = Rtc::from_footer(&footer, ClockSource::Fixed(time), 0).unwrap();
+        assert_eq!(loaded.live, [0, 30, 12, 44, 1]);
+        assert!("1:24:00:00".parse::<RtcTime>().is_err());
+    }
+}
@@ -0,0 +1,120 @@
+// Battery backed cartridge RAM on disk. Saves are written to a temporary file
+// then renamed over the previous one, which is kept as a backup: a crash
+// during the write never leaves a truncated save.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+pub struct BatterySave {
+    path: PathBuf,
+    dirty: bool,
+}
+
+impl BatterySave {
+    // Returns the saved data if any, from the backup if the save is missing.
+    // Saves of older versions are read from the legacy path, but written to
+    // the new one.
+    pub fn open(path: PathBuf, legacy: &Path) -> Result<(Self, Option<Vec<u8>>)> {
+        let mut data = read_if_exists(&path)?;
+        if data.is_none() {
+            data = read_if_exists(&backup_path(&path))?;
+            if data.is_some() {
+                println!("Save file restored from {}", backup_path(&path).display());
+            }
+        }
+        if data.is_none() {
+            data = read_if_exists(legacy)?;
+            if data.is_some() {
+                println!("Save file imported from {}", legacy.display());
+            }
+        }
+        match data {
+            Some(_) => println!("Save file loaded from {}", path.display()),
+            None => println!("Game will be saved to {}", path.display()),
+        }
+        Ok((BatterySave { path, dirty: false }, data))
+    }
+
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    // Write the data if it changed since the last flush
+    pub fn flush(&mut self, data: &[u8]) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = with_suffix(&self.path, ".tmp");
+        let mut file = File::create(&tmp)
+            .with_context(|| format!("Cannot create {}", tmp.display()))?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        drop(file);
+        if self.path.exists() {
+            fs::rename(&self.path, backup_path(&self.path))?;
+        }
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Cannot rename {} to {}", tmp.display(), self.path.display()))?;
+        self.dirty = false;
+        println!("Game saved!");
+        Ok(())
+    }
+}
+
+fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Cannot read save file {}", path.display())),
+    }
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".bak")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut res = OsString::from(path);
+    res.push(suffix);
+    res.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_dir::TestDir;
+
+    #[test]
+    fn flush_keeps_one_backup() {
+        let dir = TestDir::new("save");
+        let path = dir.join("game.sav");
+
+        let legacy = dir.join("game.old");
+        let (mut save, data) = BatterySave::open(path.clone(), &legacy).unwrap();
+        assert!(data.is_none());
+        save.flush(&[1]).unwrap();
+        assert!(!path.exists(), "clean RAM is not written");
+        save.mark_dirty();
+        save.flush(&[1]).unwrap();
+        save.mark_dirty();
+        save.flush(&[2]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [2]);
+        assert_eq!(fs::read(backup_path(&path)).unwrap(), [1]);
+
+        fs::remove_file(&path).unwrap();
+        let (_, data) = BatterySave::open(path.clone(), &legacy).unwrap();
+        assert_eq!(data.unwrap(), [1]);
+
+        fs::remove_file(backup_path(&path)).unwrap();
+        fs::write(&legacy, [3]).unwrap();
+        let (_, data) = BatterySave::open(path, &legacy).unwrap();
+        assert_eq!(data.unwrap(), [3]);
+    }
+}
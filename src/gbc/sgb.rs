@@ -0,0 +1,380 @@
+// Super Game Boy: the SNES side colorizes the 4 shades of the Game Boy screen
+// with 4 palettes chosen per 8x8 cell, and draws a 256x224 border around it.
+// Games control it with command packets sent through the joypad register.
+
+use super::palette::{rgb555, Rgb};
+use super::ppu::Ppu;
+use crate::gui;
+
+const COLUMNS: usize = gui::WIDTH / 8;
+const ROWS: usize = gui::HEIGHT / 8;
+const ATTR_FILE_SIZE: usize = COLUMNS * ROWS / 4;
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+// Palette loaded by the SGB BIOS
+const DEFAULT_PALETTE: [u16; 4] = [0x67bf, 0x265b, 0x10b5, 0x2866];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mask {
+    None,
+    Freeze,
+    Black,
+    Color0,
+}
+
+#[derive(Clone, Copy)]
+enum Transfer {
+    Palettes,
+    Tiles { high: bool },
+    Border,
+    Attributes,
+}
+
+pub struct Sgb {
+    packets: Vec<u8>,
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u16>, // 512 palettes sent by PAL_TRN
+    attributes: [u8; COLUMNS * ROWS],
+    attribute_files: Vec<u8>, // 45 files sent by ATTR_TRN
+    border_tiles: Vec<u8>,    // 256 SNES 4bpp tiles
+    border_map: Vec<u8>,      // 32x32 entries followed by palettes 4-7
+    mask: Mask,
+    players: u8,
+    transfer: Option<Transfer>,
+    screen: Vec<u8>, // last displayed frame, kept while frozen
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Sgb {
+            packets: Vec::new(),
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; 512 * 4],
+            attributes: [0; COLUMNS * ROWS],
+            attribute_files: vec![0; 45 * ATTR_FILE_SIZE],
+            border_tiles: vec![0; 0x2000],
+            border_map: vec![0; 0x880],
+            mask: Mask::None,
+            players: 1,
+            transfer: None,
+            screen: vec![0; gui::WIDTH * gui::HEIGHT],
+        }
+    }
+
+    pub fn players(&self) -> u8 {
+        self.players
+    }
+
+    // Commands are 1 to 7 packets long, the first byte gives the count
+    pub fn receive(&mut self, packet: [u8; 16]) {
+        self.packets.extend_from_slice(&packet);
+        let length = (self.packets[0] & 0x07).max(1) as usize;
+        if self.packets.len() >= length * 16 {
+            let command = std::mem::take(&mut self.packets);
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            0x00 => self.set_palette_pair(0, 1, data),
+            0x01 => self.set_palette_pair(2, 3, data),
+            0x02 => self.set_palette_pair(0, 3, data),
+            0x03 => self.set_palette_pair(1, 2, data),
+            0x04 => self.attr_blk(data),
+            0x05 => self.attr_lin(data),
+            0x06 => self.attr_div(data),
+            0x07 => self.attr_chr(data),
+            0x0a => self.pal_set(data),
+            0x0b => self.transfer = Some(Transfer::Palettes),
+            0x11 => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                }
+            }
+            0x13 => {
+                self.transfer = Some(Transfer::Tiles {
+                    high: data[1] & 0x01 != 0,
+                })
+            }
+            0x14 => self.transfer = Some(Transfer::Border),
+            0x15 => self.transfer = Some(Transfer::Attributes),
+            0x16 => {
+                self.apply_attribute_file(data[1] as usize & 0x3f);
+                if data[1] & 0x40 != 0 {
+                    self.mask = Mask::None;
+                }
+            }
+            0x17 => {
+                self.mask = match data[1] & 0x03 {
+                    0 => Mask::None,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                }
+            }
+            _ => {} // sound, SNES code and unused commands
+        }
+    }
+
+    // PAL01, PAL23, PAL03, PAL12: color 0 is shared by all palettes
+    fn set_palette_pair(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |i: usize| u16::from_le_bytes([data[1 + 2 * i], data[2 + 2 * i]]);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    fn pal_set(&mut self, data: &[u8]) {
+        for i in 0..4 {
+            let index = u16::from_le_bytes([data[1 + 2 * i], data[2 + 2 * i]]) as usize & 0x1ff;
+            self.palettes[i].copy_from_slice(&self.system_palettes[index * 4..index * 4 + 4]);
+        }
+        let color0 = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color0;
+        }
+        if data[9] & 0x80 != 0 {
+            self.apply_attribute_file(data[9] as usize & 0x3f);
+        }
+        if data[9] & 0x40 != 0 {
+            self.mask = Mask::None;
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let count = (data[1] as usize & 0x1f).min(18);
+        for set in data[2..].chunks_exact(6).take(count) {
+            let control = set[0] & 0x07;
+            let inside = set[1] & 0x03;
+            let border = (set[1] >> 2) & 0x03;
+            let outside = (set[1] >> 4) & 0x03;
+            // Changing only one side also changes the border
+            let border = match control {
+                0x01 => Some(inside),
+                0x04 => Some(outside),
+                _ if control & 0x02 != 0 => Some(border),
+                _ => None,
+            };
+            let (x1, y1) = (set[2] as usize & 0x1f, set[3] as usize & 0x1f);
+            let (x2, y2) = (set[4] as usize & 0x1f, set[5] as usize & 0x1f);
+            for y in 0..ROWS {
+                for x in 0..COLUMNS {
+                    let palette = if x > x1 && x < x2 && y > y1 && y < y2 {
+                        (control & 0x01 != 0).then_some(inside)
+                    } else if x < x1 || x > x2 || y < y1 || y > y2 {
+                        (control & 0x04 != 0).then_some(outside)
+                    } else {
+                        border
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * COLUMNS + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for &line in data[2..].iter().take(count) {
+            let index = line as usize & 0x1f;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 != 0 {
+                if index < ROWS {
+                    self.attributes[index * COLUMNS..(index + 1) * COLUMNS].fill(palette);
+                }
+            } else if index < COLUMNS {
+                for y in 0..ROWS {
+                    self.attributes[y * COLUMNS + index] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let line = data[2] as usize & 0x1f;
+        for y in 0..ROWS {
+            for x in 0..COLUMNS {
+                let position = if horizontal { y } else { x };
+                self.attributes[y * COLUMNS + x] = match position.cmp(&line) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let mut x = data[1] as usize % COLUMNS;
+        let mut y = data[2] as usize % ROWS;
+        let count = (u16::from_le_bytes([data[3], data[4]]) as usize).min(COLUMNS * ROWS);
+        let vertical = data[5] & 0x01 != 0;
+        for i in 0..count {
+            let Some(byte) = data.get(6 + i / 4) else {
+                break;
+            };
+            self.attributes[y * COLUMNS + x] = (byte >> (6 - 2 * (i % 4))) & 0x03;
+            if vertical {
+                y += 1;
+                if y == ROWS {
+                    y = 0;
+                    x = (x + 1) % COLUMNS;
+                }
+            } else {
+                x += 1;
+                if x == COLUMNS {
+                    x = 0;
+                    y = (y + 1) % ROWS;
+                }
+            }
+        }
+    }
+
+    fn apply_attribute_file(&mut self, index: usize) {
+        if index >= 45 {
+            return;
+        }
+        let file = &self.attribute_files[index * ATTR_FILE_SIZE..(index + 1) * ATTR_FILE_SIZE];
+        for (i, attribute) in self.attributes.iter_mut().enumerate() {
+            *attribute = (file[i / 4] >> (6 - 2 * (i % 4))) & 0x03;
+        }
+    }
+
+    // Called at VBlank: captures the frame and completes VRAM transfers, which
+    // read what the game displays after sending the command
+    pub fn vblank(&mut self, ppu: &Ppu) {
+        if self.mask != Mask::Freeze {
+            for y in 0..gui::HEIGHT {
+                for x in 0..gui::WIDTH {
+                    self.screen[y * gui::WIDTH + x] = ppu.shade(x, y);
+                }
+            }
+        }
+
+        let Some(transfer) = self.transfer.take() else {
+            return;
+        };
+        let data = ppu.screen_tile_data();
+        match transfer {
+            Transfer::Palettes => {
+                for (color, bytes) in self.system_palettes.iter_mut().zip(data.chunks_exact(2)) {
+                    *color = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+            }
+            Transfer::Tiles { high } => {
+                let offset = if high { 0x1000 } else { 0 };
+                self.border_tiles[offset..offset + 0x1000].copy_from_slice(&data);
+            }
+            Transfer::Border => self.border_map.copy_from_slice(&data[..0x880]),
+            Transfer::Attributes => {
+                let size = self.attribute_files.len();
+                self.attribute_files.copy_from_slice(&data[..size]);
+            }
+        }
+    }
+
+    pub fn render(&self, target: &mut [u8]) {
+        let backdrop = rgb555(self.palettes[0][0]);
+        for y in 0..gui::SGB_HEIGHT {
+            for x in 0..gui::SGB_WIDTH {
+                let on_screen = (SCREEN_X..SCREEN_X + gui::WIDTH).contains(&x)
+                    && (SCREEN_Y..SCREEN_Y + gui::HEIGHT).contains(&y);
+                let color = match self.border_pixel(x, y) {
+                    Some(color) => color,
+                    None if on_screen => self.screen_pixel(x - SCREEN_X, y - SCREEN_Y),
+                    None => backdrop,
+                };
+                let index = (y * gui::SGB_WIDTH + x) * gui::DEPTH;
+                target[index..index + 3].copy_from_slice(&[color.0, color.1, color.2]);
+            }
+        }
+    }
+
+    fn screen_pixel(&self, x: usize, y: usize) -> Rgb {
+        let palette = &self.palettes[self.attributes[y / 8 * COLUMNS + x / 8] as usize];
+        match self.mask {
+            Mask::Black => (0, 0, 0),
+            Mask::Color0 => rgb555(palette[0]),
+            Mask::None | Mask::Freeze => rgb555(palette[self.screen[y * gui::WIDTH + x] as usize]),
+        }
+    }
+
+    // None where the border is transparent
+    fn border_pixel(&self, x: usize, y: usize) -> Option<Rgb> {
+        let entry_addr = (y / 8 * 32 + x / 8) * 2;
+        let entry =
+            u16::from_le_bytes([self.border_map[entry_addr], self.border_map[entry_addr + 1]]);
+        let tile = (entry & 0xff) as usize;
+        let palette = (entry >> 10) as usize & 0x03; // palettes 4-7
+        let tile_x = if entry & 0x4000 != 0 { 7 - x % 8 } else { x % 8 };
+        let tile_y = if entry & 0x8000 != 0 { 7 - y % 8 } else { y % 8 };
+
+        let row = tile * 32 + tile_y * 2;
+        let planes = [row, row + 1, row + 16, row + 17];
+        let color = planes
+            .iter()
+            .enumerate()
+            .fold(0, |acc, (i, &addr)| acc | ((self.border_tiles[addr] >> (7 - tile_x)) & 1) << i);
+        if color == 0 {
+            return None;
+        }
+        let addr = 0x800 + (palette * 16 + color as usize) * 2;
+        Some(rgb555(u16::from_le_bytes([self.border_map[addr], self.border_map[addr + 1]])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(bytes: &[u8]) -> [u8; 16] {
+        let mut res = [0; 16];
+        res[..bytes.len()].copy_from_slice(bytes);
+        res
+    }
+
+    #[test]
+    fn pal01_shares_color_0() {
+        let mut sgb = Sgb::new();
+        sgb.receive(packet(&[0x01, 0x1f, 0x00, 0x01, 0x00, 0x02, 0x00, 0x03, 0x00, 0x04, 0x00]));
+        assert_eq!(sgb.palettes[0], [0x001f, 0x0001, 0x0002, 0x0003]);
+        assert_eq!(sgb.palettes[1][..2], [0x001f, 0x0004]);
+        assert_eq!(sgb.palettes[3][0], 0x001f);
+    }
+
+    #[test]
+    fn attr_blk_inside_only_also_sets_border() {
+        let mut sgb = Sgb::new();
+        sgb.receive(packet(&[0x21, 0x01, 0x01, 0x02, 2, 2, 5, 5]));
+        assert_eq!(sgb.attributes[3 * COLUMNS + 3], 2);
+        assert_eq!(sgb.attributes[2 * COLUMNS + 2], 2);
+        assert_eq!(sgb.attributes[5 * COLUMNS + 5], 2);
+        assert_eq!(sgb.attributes[6 * COLUMNS + 6], 0);
+    }
+
+    #[test]
+    fn multi_packet_attr_chr() {
+        let mut sgb = Sgb::new();
+        // 20 cells of palette 3 on the second row
+        let mut first = packet(&[0x3a, 0, 1, 20, 0, 0]);
+        first[6..].fill(0xff);
+        sgb.receive(first);
+        assert_eq!(sgb.attributes[COLUMNS], 0);
+        sgb.receive(packet(&[]));
+        assert!(sgb.attributes[COLUMNS..2 * COLUMNS].iter().all(|&a| a == 3));
+        assert_eq!(sgb.attributes[2 * COLUMNS], 0);
+    }
+}
@@ -0,0 +1,31 @@
+// Scratch directory for the tests that need files, removed when dropped so
+// a failed assertion does not leave it behind
+
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    // The name must be unique among the tests, which run in parallel
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("gbcemu-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
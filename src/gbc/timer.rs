@@ -73,6 +73,10 @@ impl Timer {
         self.div = 0;
     }
 
+    pub fn set_div(&mut self, div: u8) {
+        self.div = div;
+    }
+
     pub fn get_div(&self) -> u8 {
         self.div
     }
@@ -0,0 +1,134 @@
+// VGM logging of the APU register writes, timed in samples at 44100 Hz like
+// the format wants. The commands are kept in memory and the file is written
+// when the log is finished.
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use super::apu::CPU_FREQ;
+
+const VGM_RATE: u64 = 44100;
+const VERSION: u32 = 0x161; // first version with the Game Boy chip
+const HEADER_SIZE: usize = 0x100;
+
+pub struct VgmLog {
+    path: PathBuf,
+    data: Vec<u8>,
+    cycles: u64,
+    samples: u64, // waited so far
+    finished: bool,
+}
+
+impl VgmLog {
+    // Starts with the given writes, to restore the current APU state
+    pub fn new(path: &Path, state: &[(u16, u8)]) -> Result<Self> {
+        // Created now to report a bad path right away
+        File::create(path).with_context(|| format!("Cannot create {}", path.display()))?;
+        let mut log = VgmLog {
+            path: path.to_owned(),
+            data: vec![],
+            cycles: 0,
+            samples: 0,
+            finished: false,
+        };
+        for &(addr, val) in state {
+            log.write(addr, val);
+        }
+        println!("Logging sound to {}", path.display());
+        Ok(log)
+    }
+
+    // Called every T-cycle
+    pub fn tick(&mut self) {
+        self.cycles += 1;
+    }
+
+    // Registers 0xff10 to 0xff3f
+    pub fn write(&mut self, addr: u16, val: u8) {
+        self.wait();
+        self.data.extend([0xb3, (addr - 0xff10) as u8, val]);
+    }
+
+    fn wait(&mut self) {
+        let now = self.cycles * VGM_RATE / CPU_FREQ as u64;
+        let mut wait = now - self.samples;
+        self.samples = now;
+        while wait > 0 {
+            let n = wait.min(0xffff);
+            if n <= 16 {
+                self.data.push(0x70 + n as u8 - 1);
+            } else {
+                self.data.push(0x61);
+                self.data.extend((n as u16).to_le_bytes());
+            }
+            wait -= n;
+        }
+    }
+
+    // Also done when dropped, but without reporting errors
+    pub fn finish(mut self) -> Result<()> {
+        self.write_file()
+    }
+
+    fn write_file(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.wait();
+        self.data.push(0x66); // end of data
+
+        let mut header = [0u8; HEADER_SIZE];
+        let mut set = |offset: usize, value: u32| {
+            header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        set(0x00, u32::from_le_bytes(*b"Vgm "));
+        set(0x04, (HEADER_SIZE + self.data.len() - 4) as u32);
+        set(0x08, VERSION);
+        set(0x18, self.samples as u32);
+        set(0x34, (HEADER_SIZE - 0x34) as u32); // data offset, from this field
+        set(0x80, CPU_FREQ); // Game Boy DMG clock
+        let mut file = header.to_vec();
+        file.extend(&self.data);
+        fs::write(&self.path, file).with_context(|| format!("Cannot write {}", self.path.display()))
+    }
+}
+
+impl Drop for VgmLog {
+    fn drop(&mut self) {
+        let _ = self.write_file();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_dir::TestDir;
+
+    #[test]
+    fn commands_and_header() {
+        let dir = TestDir::new("vgm");
+        let path = dir.join("song.vgm");
+        let mut log = VgmLog::new(&path, &[(0xff26, 0x80)]).unwrap();
+        for _ in 0..CPU_FREQ / 4410 * 3 {
+            log.tick();
+        }
+        log.write(0xff12, 0xf0);
+        for _ in 0..CPU_FREQ {
+            log.tick();
+        }
+        log.finish().unwrap();
+
+        let data = fs::read(&path).unwrap();
+        assert_eq!(&data[..4], b"Vgm ");
+        assert_eq!(data[0x04..0x08], (data.len() as u32 - 4).to_le_bytes());
+        assert_eq!(data[0x18..0x1c], 44129u32.to_le_bytes());
+        assert_eq!(data[0x80..0x84], CPU_FREQ.to_le_bytes());
+        assert_eq!(
+            data[HEADER_SIZE..],
+            [0xb3, 0x16, 0x80, 0x61, 29, 0, 0xb3, 0x02, 0xf0, 0x61, 0x44, 0xac, 0x66]
+        );
+    }
+}
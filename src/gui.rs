@@ -1,18 +1,55 @@
 use sdl2::GameControllerSubsystem;
-use sdl2::controller::Button;
+use sdl2::controller::{Axis, Button};
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
 use sdl2::pixels::PixelFormatEnum;
 use std::time::{Duration, Instant};
 use std::env;
-use anyhow::Result;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use anyhow::{bail, Result};
 
+use crate::gbc::apu::CPU_FREQ;
+use crate::gbc::sound::TARGET_FILL;
 use crate::gbc::Emu;
 
 pub const WIDTH: usize = 160;
 pub const HEIGHT: usize = 144;
 pub const DEPTH: usize = 3;
 pub const SIZE: usize = WIDTH * HEIGHT * DEPTH;
+// Super Game Boy frame, the screen is surrounded by the border
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+// 59.73 Hz
+const FRAME_TIME: Duration = Duration::from_nanos(70224 * 1_000_000_000 / CPU_FREQ as u64);
+
+// Master volume change of the - and = keys
+const VOLUME_STEP: f32 = 0.1;
+
+// What sets the emulation speed. The audio resampling follows the pace
+// within 0.5%, so it never crackles with either.
+#[derive(Clone, Copy, Default)]
+pub enum Pacing {
+    // Wait for the audio device to play the queued samples
+    #[default]
+    Audio,
+    // Wait for the display refresh, and sleep when it is faster than the
+    // game like on 120 Hz screens
+    Vsync,
+}
+
+impl FromStr for Pacing {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "audio" => Ok(Pacing::Audio),
+            "vsync" => Ok(Pacing::Vsync),
+            _ => bail!("Unknown pacing {s}, expected audio or vsync"),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 pub enum GBKey {
@@ -36,13 +73,13 @@ pub struct Gui {
     gamepad_subsystem: GameControllerSubsystem,
     canvas: sdl2::render::Canvas<sdl2::video::Window>,
     emu: Emu,
-
-    last_time: Instant,
-    last_sleep: Duration,
+    quit: Arc<AtomicBool>, // set by SIGINT and SIGTERM
+    pacing: Pacing,
+    next_frame: Instant, // with Vsync pacing
 }
 
 impl Gui {
-    pub fn new(emu: Emu) -> Result<Self> {
+    pub fn new(emu: Emu, quit: Arc<AtomicBool>, pacing: Pacing) -> Result<Self> {
         sdl2::hint::set("SDL_VIDEO_X11_NET_WM_BYPASS_COMPOSITOR", "0");
         let sdl_context = sdl2::init().map_err(|e|anyhow::anyhow!(e))?;
         let video_subsystem = sdl_context.video().map_err(|e|anyhow::anyhow!(e))?;
@@ -51,12 +88,16 @@ impl Gui {
             gamepad_subsystem.load_mappings(&p)?;
             println!("Loaded mapping from {p}");
         }
+        let (width, height) = emu.screen_size();
         let window = video_subsystem
-            .window("yaGBemu", WIDTH as u32 * 4, HEIGHT as u32 * 4)
+            .window("yaGBemu", width as u32 * 4, height as u32 * 4)
             .position_centered()
             .build()?;
 
-        let mut canvas = window.into_canvas().build()?;
+        let mut canvas = match pacing {
+            Pacing::Audio => window.into_canvas().build()?,
+            Pacing::Vsync => window.into_canvas().present_vsync().build()?,
+        };
 
         canvas.clear();
         canvas.present();
@@ -66,9 +107,9 @@ impl Gui {
             gamepad_subsystem,
             canvas,
             emu,
-
-            last_time: Instant::now(),
-            last_sleep: Duration::from_millis(0),
+            quit,
+            pacing,
+            next_frame: Instant::now(),
         })
     }
     pub fn run(&mut self) {
@@ -82,14 +123,20 @@ impl Gui {
             }
         }
 
+        let (width, height) = self.emu.screen_size();
         let texture_creator = self.canvas.texture_creator();
         let mut texture = texture_creator
-            .create_texture_static(PixelFormatEnum::RGB24, WIDTH as u32, HEIGHT as u32)
+            .create_texture_static(PixelFormatEnum::RGB24, width as u32, height as u32)
             .expect("Could not allocate texture");
 
-        let mut render_target = Box::new([0u8; SIZE]);
+        let mut render_target = vec![0u8; width * height * DEPTH];
+        // Tilt of MBC7 cartridges, from the mouse position or the left stick
+        let mut tilt = (0.0, 0.0);
 
         'running: loop {
+            if self.quit.load(Ordering::Relaxed) {
+                break 'running;
+            }
             let mut events = vec![];
 
             for event in event_pump.poll_iter() {
@@ -195,6 +242,73 @@ impl Gui {
                     } => {
                         events.push(Message::KeyUp(GBKey::Down));
                     }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::P),
+                        ..
+                    } => {
+                        self.emu.cycle_palette();
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::R),
+                        ..
+                    } => {
+                        self.emu.toggle_recording();
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::PageDown),
+                        ..
+                    } => {
+                        self.emu.select_track(1);
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::PageUp),
+                        ..
+                    } => {
+                        self.emu.select_track(-1);
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::V),
+                        ..
+                    } => {
+                        self.emu.toggle_vgm();
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::M),
+                        ..
+                    } => {
+                        self.emu.toggle_mute();
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Minus),
+                        ..
+                    } => {
+                        let volume = self.emu.master_volume() - VOLUME_STEP;
+                        self.emu.set_master_volume(volume);
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Equals),
+                        ..
+                    } => {
+                        let volume = self.emu.master_volume() + VOLUME_STEP;
+                        self.emu.set_master_volume(volume);
+                    }
+                    // 1 to 4 mute a channel, with shift they solo it and with
+                    // ctrl they halve its gain, down to a quarter then back
+                    Event::KeyDown {
+                        keycode: Some(key @ (Keycode::Num1 | Keycode::Num2 | Keycode::Num3 | Keycode::Num4)),
+                        keymod,
+                        ..
+                    } => {
+                        let channel = key as usize - Keycode::Num1 as usize;
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            self.emu.toggle_channel_solo(channel);
+                        } else if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) {
+                            let gain = self.emu.channel_gain(channel) / 2.0;
+                            self.emu.set_channel_gain(channel, if gain < 0.25 { 1.0 } else { gain });
+                        } else {
+                            self.emu.toggle_channel_mute(channel);
+                        }
+                    }
                     Event::ControllerButtonDown { button, .. } => {
                         events.push(
                             if let Some(gb_key) = controller_to_gb_key(&button) {
@@ -213,6 +327,21 @@ impl Gui {
                             }
                         )
                     }
+                    Event::MouseMotion { x, y, .. } => {
+                        let (w, h) = self.canvas.window().size();
+                        tilt = (
+                            (2.0 * x as f32 / w as f32 - 1.0),
+                            (2.0 * y as f32 / h as f32 - 1.0),
+                        );
+                    }
+                    Event::ControllerAxisMotion { axis, value, .. } => {
+                        let value = value as f32 / i16::MAX as f32;
+                        match axis {
+                            Axis::LeftX => tilt.0 = value,
+                            Axis::LeftY => tilt.1 = value,
+                            _ => {}
+                        }
+                    }
                     Event::ControllerDeviceAdded { which,.. } => {
                         println!("Added gamepad {}", self.gamepad_subsystem.name_for_index(which).unwrap());
                         gamepads.push(self.gamepad_subsystem.open(which).unwrap());
@@ -227,29 +356,48 @@ impl Gui {
                 }
             }
             // The rest of the game loop goes here...
-            self.emu.get_next_frame(&events, &mut render_target);
+            self.emu.set_tilt(tilt.0, tilt.1);
+            if let Err(e) = self.emu.get_next_frame(&events, &mut render_target) {
+                eprintln!("Emulation error: {e}");
+                let _ = self.canvas.window_mut().set_title(&format!("yaGBemu - {e}"));
+            }
             texture
-                .update(None, &render_target[..], WIDTH * DEPTH)
+                .update(None, &render_target[..], width * DEPTH)
                 .expect("Could not update texture");
             self.canvas
                 .copy(&texture, None, None)
                 .expect("Could not render texture");
             self.canvas.present();
 
-            let last_time = self.last_time;
-            self.last_time = Instant::now();
-            let elapsed = self.last_time.duration_since(last_time).as_micros();
-            let sleep = self.last_sleep.as_micros() as i128 + (16_666 - elapsed as i128);
-            //println!("Fps: {}", 1e6 / elapsed as f64);
-            if sleep > 0 {
-                self.last_sleep = Duration::from_micros(sleep as u64);
-                std::thread::sleep(self.last_sleep);
-            } else {
-                self.last_sleep = Duration::from_micros(0);
-                std::thread::yield_now();
+            match self.pacing {
+                Pacing::Audio => self.wait_for_audio(),
+                Pacing::Vsync => self.limit_frame_rate(),
             }
         }
     }
+
+    // Wait for the audio queue to drain to its target. A stalled device
+    // slows the game down but does not freeze it.
+    fn wait_for_audio(&self) {
+        let deadline = Instant::now() + FRAME_TIME * 2;
+        while self.emu.audio_fill() > TARGET_FILL && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    // Sleep out the rest of FRAME_TIME, in case the refresh is faster or
+    // vsync is ignored. The deadline moves by whole frames so waits for a
+    // late refresh average out.
+    fn limit_frame_rate(&mut self) {
+        self.next_frame += FRAME_TIME;
+        let now = Instant::now();
+        if self.next_frame > now {
+            std::thread::sleep(self.next_frame - now);
+        } else if now - self.next_frame > FRAME_TIME {
+            // Too far behind to catch up, after a stall
+            self.next_frame = now;
+        }
+    }
 }
 
 fn controller_to_gb_key(sdl_key: &Button) -> Option<GBKey> {
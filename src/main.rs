@@ -1,26 +1,136 @@
 mod gbc;
 mod gui;
 
-use gbc::Emu;
+use gbc::cartridge::{export_save, import_save};
+use gbc::{Config, Emu};
 
-use gui::{Gui};
+use gui::{Gui, Pacing};
 
 
 
 use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-use anyhow::{Result, bail};
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+use anyhow::{Context, Result, bail};
 
 fn main() -> Result<()>{
 
-    if env::args().count() != 2 {
+    let mut config = Config::default();
+    let mut pacing = Pacing::default();
+    let mut positional = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--boot-rom" => config.boot_rom = Some(args.next().context("--boot-rom expects a path")?),
+            "--model" => {
+                let model = args.next().context("--model expects dmg, mgb, sgb, cgb, agb or auto")?;
+                config.model = if model == "auto" { None } else { Some(model.parse()?) };
+            }
+            "--palette" => {
+                let name = args
+                    .next()
+                    .context("--palette expects a button combo like up-a or left")?;
+                let index = gbc::palette::find_manual(&name)
+                    .with_context(|| format!("Unknown palette {name}"))?;
+                config.palette = Some(index);
+            }
+            "--rtc-clock" => {
+                let clock = args.next().context("--rtc-clock expects host, emulated or days:hh:mm:ss")?;
+                config.clock = clock.parse()?;
+            }
+            "--set-rtc" => {
+                let time = args.next().context("--set-rtc expects days:hh:mm:ss")?;
+                config.set_rtc = Some(time.parse()?);
+            }
+            "--save-dir" => {
+                let dir = args.next().context("--save-dir expects a directory")?;
+                config.save_dir = Some(dir.into());
+            }
+            "--pacing" => {
+                pacing = args.next().context("--pacing expects audio or vsync")?.parse()?;
+            }
+            "--zip-entry" => {
+                config.zip_entry = Some(args.next().context("--zip-entry expects a file name")?);
+            }
+            "--multicart" => {
+                config.multicart = match args.next().as_deref() {
+                    Some("on") => Some(true),
+                    Some("off") => Some(false),
+                    Some("auto") => None,
+                    _ => bail!("--multicart expects on, off or auto"),
+                };
+            }
+            "--audio-filter" => {
+                let filter = args.next().context("--audio-filter expects off, dmg, cgb or auto")?;
+                config.audio_filter = if filter == "auto" { None } else { Some(filter.parse()?) };
+            }
+            "--audio-device" => {
+                config.audio_device = Some(args.next().context("--audio-device expects a device name")?);
+            }
+            "--audio-latency" => {
+                let latency = args.next().context("--audio-latency expects milliseconds")?;
+                let latency: u32 = latency.parse().context("--audio-latency expects milliseconds")?;
+                if !(1..=1000).contains(&latency) {
+                    bail!("--audio-latency expects 1 to 1000 milliseconds");
+                }
+                config.audio_latency = Some(latency);
+            }
+            "--list-audio-devices" => return gbc::sound::list_devices(),
+            "--headless" => config.headless = true,
+            "--record-audio" => {
+                config.record_audio = Some(args.next().context("--record-audio expects a path")?.into());
+            }
+            "--record-stems" => config.record_stems = true,
+            "--record-vgm" => {
+                config.record_vgm = Some(args.next().context("--record-vgm expects a path")?.into());
+            }
+            x if x.starts_with("--") => bail!("Unknown option {x}"),
+            _ => positional.push(arg),
+        }
+    }
+
+    // Move saves between gbcemu, other emulators and flash carts
+    match positional.as_slice() {
+        [command, rom, dump] if command == "import" => return import_save(rom, dump, &config),
+        [command, rom, dump] if command == "export" => return export_save(rom, dump, &config),
+        [command, ..] if command == "import" || command == "export" => {
+            bail!("Usage: gbcemu {command} ROM.GB RAM.sav")
+        }
+        _ => {}
+    }
+    let [rom_name] = positional.as_slice() else {
         bail!("Please enter the path to ROM.GB");
+    };
+
+    // Registered before SDL, which then leaves these signals alone. The GUI
+    // quits normally so the game is saved.
+    let quit = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register(signal, quit.clone())?;
     }
 
-    let rom_name = env::args().nth(1).unwrap();
-    let emu = Emu::new(&rom_name)?;
-    let mut gui = Gui::new(emu)?;
+    let emu = Emu::new(rom_name, &config)?;
+    if config.headless {
+        run_headless(emu, &quit);
+        return Ok(());
+    }
+    let mut gui = Gui::new(emu, quit, pacing)?;
     gui.run();
     Ok(())
 }
 
+// As fast as possible until interrupted, for recording audio
+fn run_headless(mut emu: Emu, quit: &AtomicBool) {
+    let (width, height) = emu.screen_size();
+    let mut frame = vec![0; width * height * gui::DEPTH];
+    while !quit.load(Ordering::Relaxed) {
+        if let Err(e) = emu.get_next_frame(&[], &mut frame) {
+            eprintln!("Emulation error: {e}");
+            break;
+        }
+    }
+}
+